@@ -1,11 +1,10 @@
-import init, { list_methods, prepare_contract, Logic, Context, Store, init_panic_hook, DebugExternal } from "./pkg/neardebug.js";
+import init, { list_methods, analyze_contract, latest_protocol_version, create_memory, Logic, RuntimeConfig, Context, World, init_panic_hook, DebugExternal, ReceiptScheduler } from "./pkg/neardebug.js";
 
 (function(window, document) {
     async function make_context() {
         const input = document.querySelector("#input");
+        const promise_results = document.querySelector("#promise_results");
         const attached_deposit = document.querySelector("#attached_deposit");
-        const balance = document.querySelector("#balance");
-        const locked_balance = document.querySelector("#locked_balance");
         const current_account = document.querySelector("#current_account");
         const signer_account = document.querySelector("#signer_account");
         const signer_account_pk = document.querySelector("#signer_account_pk");
@@ -15,11 +14,11 @@ import init, { list_methods, prepare_contract, Logic, Context, Store, init_panic
         const epoch_height = document.querySelector("#epoch_height");
         const random_seed = document.querySelector("#random_seed");
         const gas = document.querySelector("#gas");
-        const context = new Context()
+        const view = document.querySelector("#view");
+        const max_gas_burnt_view = document.querySelector("#max_gas_burnt_view");
+        let context = new Context()
             .input_str(input.value || input.placeholder)
             .attached_deposit(attached_deposit.value || attached_deposit.placeholder)
-            .balance(balance.value || balance.placeholder)
-            .locked_balance(locked_balance.value || locked_balance.placeholder)
             .current_account(current_account.value || current_account.placeholder)
             .signer_account(signer_account.value || signer_account.placeholder)
             .signer_account_pk(signer_account_pk.value || signer_account_pk.placeholder)
@@ -30,149 +29,97 @@ import init, { list_methods, prepare_contract, Logic, Context, Store, init_panic
             .random_seed(random_seed.value || random_seed.placeholder)
             .gas(gas.value || gas.placeholder)
             ;
+        if (promise_results.value.trim() !== "") {
+            context = context.promise_results(promise_results.value);
+        }
+        if (view.checked) {
+            context = context.view(max_gas_burnt_view.value || max_gas_burnt_view.placeholder);
+        }
         return context;
     }
 
-    async function run(method_name) {
-        const contract = window.contract;
-        const memory = new WebAssembly.Memory({ initial: 1024, maximum: 2048 });
-        contract.memory = memory;
-        const protocol_version = 72;
-        const context = await make_context();
-        const ext = new DebugExternal(contract.store, context, protocol_version);
-        const logic = new Logic(context, memory, ext);
-        contract.logic = logic;
-
-        const import_object = { env: {} };
-        import_object.internal = {
-            finite_wasm_gas: (...args) => logic.finite_wasm_gas(...args),
-            finite_wasm_stack: (...args) => logic.finite_wasm_stack(...args),
-            finite_wasm_unstack: (...args) => logic.finite_wasm_unstack(...args),
-        };
-        import_object.env = {
-            memory: memory,
-            log_utf8: (len, ptr) => {
-                try {
-                    console.log(logic.get_utf8_string_free(len, ptr));
-                } catch { }
-                return logic.log_utf8(len, ptr);
-            },
-
-            log_utf16: (len, ptr) => {
-                console.log("TODO: output log_utf16 to console");
-                return logic.log_utf16(len, ptr)
-            },
+    // Set the hashes of the chain the call is executed on, from which the ids of the receipts and
+    // data (and the random seed, if the random value of the block is given) are derived.
+    function with_chain_hashes(ext) {
+        const action_hash = document.querySelector("#action_hash");
+        const prev_block_hash = document.querySelector("#prev_block_hash");
+        const block_hash = document.querySelector("#block_hash");
+        const random_value = document.querySelector("#random_value");
+        ext = ext
+            .with_action_hash(action_hash.value || action_hash.placeholder)
+            .with_block_hashes(
+                prev_block_hash.value || prev_block_hash.placeholder,
+                block_hash.value || block_hash.placeholder,
+            );
+        if (random_value.value.trim() !== "") {
+            ext = ext.with_random_value(random_value.value.trim());
+        }
+        return ext;
+    }
 
-            read_register: (...args) => logic.read_register(...args),
-            register_len: (...args) => logic.register_len(...args),
-            write_register: (...args) => logic.write_register(...args),
-            current_account_id: (...args) => logic.current_account_id(...args),
-            signer_account_id: (...args) => logic.signer_account_id(...args),
-            signer_account_pk: (...args) => logic.signer_account_pk(...args),
-            predecessor_account_id: (...args) => logic.predecessor_account_id(...args),
-            input: (...args) => logic.input(...args),
-            block_index: (...args) => logic.block_index(...args),
-            block_timestamp: (...args) => logic.block_timestamp(...args),
-            epoch_height: (...args) => logic.epoch_height(...args),
-            storage_usage: (...args) => logic.storage_usage(...args),
-            account_balance: (...args) => logic.account_balance(...args),
-            account_locked_balance: (...args) => logic.account_locked_balance(...args),
-            attached_deposit: (...args) => logic.attached_deposit(...args),
-            prepaid_gas: (...args) => logic.prepaid_gas(...args),
-            used_gas: (...args) => logic.used_gas(...args),
-            random_seed: (...args) => logic.random_seed(...args),
-            sha256: (...args) => logic.sha256(...args),
-            keccak256: (...args) => logic.keccak256(...args),
-            keccak512: (...args) => logic.keccak512(...args),
-            ed25519_verify: (...args) => logic.ed25519_verify(...args),
-            ripemd160: (...args) => logic.ripemd160(...args),
-            ecrecover: (...args) => logic.ecrecover(...args),
-            promise_create: (...args) => logic.promise_create(...args),
-            promise_then: (...args) => logic.promise_then(...args),
-            promise_and: (...args) => logic.promise_and(...args),
-            promise_batch_create: (...args) => logic.promise_batch_create(...args),
-            promise_batch_then: (...args) => logic.promise_batch_then(...args),
-            promise_batch_action_create_account: (...args) => logic.promise_batch_action_create_account(...args),
-            promise_batch_action_deploy_contract: (...args) => logic.promise_batch_action_deploy_contract(...args),
-            promise_batch_action_function_call: (...args) => logic.promise_batch_action_function_call(...args),
-            promise_batch_action_function_call_weight: (...args) => logic.promise_batch_action_function_call_weight(...args),
-            promise_batch_action_transfer: (...args) => logic.promise_batch_action_transfer(...args),
-            promise_batch_action_stake: (...args) => logic.promise_batch_action_stake(...args),
-            promise_batch_action_add_key_with_full_access: (...args) => logic.promise_batch_action_add_key_with_full_access(...args),
-            promise_batch_action_add_key_with_function_call: (...args) => logic.promise_batch_action_add_key_with_function_call(...args),
-            promise_batch_action_delete_key: (...args) => logic.promise_batch_action_delete_key(...args),
-            promise_batch_action_delete_account: (...args) => logic.promise_batch_action_delete_account(...args),
-            promise_yield_create: (...args) => logic.promise_yield_create(...args),
-            promise_yield_resume: (...args) => logic.promise_yield_resume(...args),
-            promise_results_count: () => logic.promise_results_count(...args),
-            promise_result: (...args) => logic.promise_result(...args),
-            promise_return: (...args) => logic.promise_return(...args),
-            value_return: (...args) => logic.value_return(...args),
-            panic: (...args) => logic.panic(...args),
-            panic_utf8: (...args) => logic.panic_utf8(...args),
-            abort: (...args) => logic.abort(...args),
-            storage_write: (...args) => logic.storage_write(...args),
-            storage_read: (...args) => logic.storage_read(...args),
-            storage_remove: (...args) => logic.storage_remove(...args),
-            storage_has_key: (...args) => logic.storage_has_key(...args),
-            storage_iter_prefix: (...args) => logic.storage_iter_prefix(...args),
-            storage_iter_range: (...args) => logic.storage_iter_range(...args),
-            storage_iter_next: (...args) => logic.storage_iter_next(...args),
-            gas: (...args) => logic.gas(...args),
-            burn_gas: (...args) => logic.burn_gas(...args),
-            validator_stake: (...args) => logic.validator_stake(...args),
-            validator_total_stake: (...args) => logic.validator_total_stake(...args),
-            alt_bn128_g1_multiexp: (...args) => logic.alt_bn128_g1_multiexp(...args),
-            alt_bn128_g1_sum: (...args) => logic.alt_bn128_g1_sum(...args),
-            alt_bn128_pairing_check: (...args) => logic.alt_bn128_pairing_check(...args),
-            bls12381_p1_sum: (...args) => logic.bls12381_p1_sum(...args),
-            bls12381_p2_sum: (...args) => logic.bls12381_p2_sum(...args),
-            bls12381_g1_multiexp: (...args) => logic.bls12381_g1_multiexp(...args),
-            bls12381_g2_multiexp: (...args) => logic.bls12381_g2_multiexp(...args),
-            bls12381_map_fp_to_g1: (...args) => logic.bls12381_map_fp_to_g1(...args),
-            bls12381_map_fp2_to_g2: (...args) => logic.bls12381_map_fp2_to_g2(...args),
-            bls12381_pairing_check: (...args) => logic.bls12381_pairing_check(...args),
-            bls12381_p1_decompress: (...args) => logic.bls12381_p1_decompress(...args),
-            bls12381_p2_decompress: (...args) => logic.bls12381_p2_decompress(...args),
-            sandbox_debug_log: () => console.warn("sandbox_debug_log is not a standard host function"),
-            sleep_nanos: () => console.warn("sleep_nanos is not a standard host function"),
-        };
-        // NB: applying fees "before loading" does not 100% match the behaviour of nearcore --
-        // nearcore would apply these fees before compiling code, but in the debugger we don't yet
-        // know the method name to use at that point.
-        logic.fees_before_loading_executable(method_name, BigInt(window.contract.wasm.length));
+    // Execute `method_name` of the contract `code`.
+    //
+    // The memory, logic and instance are stored into the `target` object for inspection.
+    async function run(target, code, method_name, context, ext, config) {
+        if (document.querySelector("#trie_model").checked) {
+            ext = ext.with_trie_model();
+        }
+        const memory = create_memory(config);
+        target.memory = memory;
+        let logic = new Logic(context, memory, ext);
+        if (document.querySelector("#trace").checked) {
+            logic = logic.with_tracing();
+        }
+        target.logic = logic;
         try {
-            window.contract.instance = await WebAssembly.instantiate(window.contract.module, import_object);
-        } catch (e) {
-            if (e.message == "HostError(GasExceeded)") {
-                e.message = logic.process_gas_limit().message;
+            return logic.execute(code, method_name, memory);
+        } finally {
+            target.instance = logic.instance();
+        }
+    }
+
+    // Execute the receipts produced by the call that has just finished, for as long as there are
+    // any ready to execute.
+    async function run_receipts(scheduler, config) {
+        const contract = window.contract;
+        contract.receipts = [];
+        for (let call = scheduler.next(); call !== undefined; call = scheduler.next()) {
+            const target = { receiver_id: call.receiver_id, method_name: call.method_name };
+            contract.receipts.push(target);
+            console.group(`${call.receiver_id}: ${call.method_name}`);
+            try {
+                const ext = call.external();
+                await run(target, call.code(), call.method_name, call.context(), ext, config);
+                scheduler.complete(target.logic, undefined);
+            } catch (e) {
+                console.error(e);
+                if (target.logic === undefined) {
+                    scheduler.abort(e.message);
+                } else {
+                    scheduler.complete(target.logic, e.message);
+                }
+            } finally {
+                console.groupEnd();
             }
-            throw e;
         }
-        logic.fees_after_loading_executable(BigInt(window.contract.wasm.length));
-        try {
-            window.contract.instance.exports[method_name]();
-        } catch (e) {
-            if (e.message == "HostError(GasExceeded)") {
-                e.message = logic.process_gas_limit().message;
+        for (const outcome of scheduler.outcomes()) {
+            if (outcome.error !== null) {
+                console.warn(`receipt to ${outcome.receiver_id} failed: ${outcome.error}`);
             }
-            throw e;
+        }
+        if (scheduler.pending_count() > 0) {
+            console.warn(`${scheduler.pending_count()} receipts are still waiting for data`);
         }
     }
 
     async function load(contract_data) {
         delete contract.wasm;
-        delete contract.prepared_wasm;
         delete contract.instance;
         delete contract.memory;
         delete contract.logic;
-        if (contract_data === undefined) {
-            delete contract.module;
-            return;
+        if (contract_data !== undefined) {
+            contract.wasm = new Uint8Array(contract_data);
         }
-        contract.wasm = new Uint8Array(contract_data);
-        contract.prepared_wasm = prepare_contract(contract.wasm);
-        contract.module = await WebAssembly.compile(contract.prepared_wasm);
     }
 
     async function on_contract_change(element) {
@@ -182,13 +129,33 @@ import init, { list_methods, prepare_contract, Logic, Context, Store, init_panic
         method_selector.innerHTML = "";
         if (element.files.length > 0) {
             const buffer = await element.files[0].arrayBuffer();
-            const callable_methods = list_methods(new Uint8Array(buffer));
-            for (const method of callable_methods) {
+            const listing = list_methods(new Uint8Array(buffer));
+            for (const method of listing.exports) {
+                if (method.kind != "function") {
+                    continue;
+                }
                 const option = document.createElement("option");
-                option.innerText = method;
-                option.value = method;
+                option.innerText = method.name;
+                option.value = method.name;
+                if (!method.callable) {
+                    // Listed regardless, so that it is clear why the method cannot be called.
+                    option.innerText += ` (${method.not_callable.join(", ")})`;
+                    option.disabled = true;
+                    console.warn(`export ${method.name} cannot be called:`, method);
+                }
                 method_selector.appendChild(option);
             }
+            for (const im of listing.imports) {
+                if (im.unknown) {
+                    console.warn(`import ${im.module}.${im.name} is not a host function:`, im);
+                }
+            }
+            const report = analyze_contract(new Uint8Array(buffer), make_config());
+            if (report.rejected) {
+                console.warn("contract would be rejected by the runtime:", report);
+            } else {
+                console.log("contract report:", report);
+            }
             await load(buffer);
         } else {
             await load(undefined);
@@ -239,22 +206,128 @@ import init, { list_methods, prepare_contract, Logic, Context, Store, init_panic
         }
     }
 
+    function current_account_id() {
+        const current_account = document.querySelector("#current_account");
+        return current_account.value || current_account.placeholder;
+    }
+
     async function update_ui() {
-        document.querySelector("#store_size").value = window.contract.store.size();
+        const world = window.contract.world;
+        const account_id = current_account_id();
+        const size = world.has_account(account_id) ? world.store(account_id).size() : 0;
+        document.querySelector("#store_size").value = size;
+        const logic = window.contract.logic;
+        if (logic !== undefined) {
+            const diff = logic.storage_diff();
+            document.querySelector("#storage_diff").value =
+                `${diff.added.length} added, ${diff.modified.length} modified, ${diff.removed.length} removed`;
+        }
+    }
+
+    function selected_protocol_version() {
+        const protocol_version_input = document.querySelector("#protocol_version");
+        return Number(protocol_version_input.value || protocol_version_input.placeholder);
+    }
+
+    // The runtime config of the protocol version chosen in the form, with the overrides applied.
+    function make_config() {
+        const overrides = document.querySelector("#config_overrides").value.trim();
+        let config = new RuntimeConfig(selected_protocol_version());
+        if (overrides !== "") {
+            config = config.with_overrides(overrides);
+        }
+        return config;
     }
 
     async function act_execute() {
         const methods = document.querySelector("#methods");
         const method = methods.selectedOptions[0].value;
+        const contract = window.contract;
+        const account_id = current_account_id();
+        if (!contract.world.has_account(account_id)) {
+            // The balances in the form are only used for creating the account; the call is
+            // executed with the balances of the account in the world.
+            const balance = document.querySelector("#balance");
+            const locked_balance = document.querySelector("#locked_balance");
+            contract.world.create_account(account_id, balance.value || balance.placeholder);
+            contract.world.set_balance(
+                account_id,
+                balance.value || balance.placeholder,
+                locked_balance.value || locked_balance.placeholder,
+            );
+        }
+        contract.world.deploy(account_id, contract.wasm);
+        try {
+            const protocol_version = selected_protocol_version();
+            const config = make_config();
+            contract.config = config;
+            const context = await make_context();
+            const epoch_height = document.querySelector("#epoch_height");
+            contract.world.start_epoch(epoch_height.value || epoch_height.placeholder);
+            // Like the command line tool, calls are only checked against the access keys if
+            // the key is given.
+            const signer_account_pk = document.querySelector("#signer_account_pk");
+            if (signer_account_pk.value !== "") {
+                const nonce = document.querySelector("#nonce");
+                const gas_price = document.querySelector("#gas_price");
+                contract.world.verify_signer_access_key(
+                    context,
+                    method,
+                    nonce.value || undefined,
+                    gas_price.value || undefined,
+                    config,
+                );
+            }
+            const ext = with_chain_hashes(
+                new DebugExternal(contract.world, context, protocol_version)
+                    .with_runtime_config(config));
+            delete contract.logic;
+            // Like on chain, the changes made by a call that fails are reverted.
+            const snapshot = contract.world.snapshot(account_id);
+            let failed = true;
+            try {
+                await run(contract, contract.wasm, method, context, ext, config);
+                failed = false;
+            } finally {
+                if (contract.logic !== undefined) {
+                    console.log("storage changes:", contract.logic.storage_diff());
+                }
+                if (failed) {
+                    contract.world.restore(snapshot);
+                }
+            }
+            contract.scheduler = new ReceiptScheduler(contract.world, contract.logic, true);
+            const error = contract.scheduler.error();
+            if (error !== undefined) {
+                contract.world.restore(snapshot);
+                console.error(error);
+            }
+            await run_receipts(contract.scheduler, config);
+        } finally {
+            update_ui();
+        }
+    }
+
+    // Deploy the contract `wasm` code to `account_id`, creating the account if necessary.
+    async function deploy(account_id, wasm) {
+        window.contract.world.deploy(account_id, new Uint8Array(wasm));
+    }
+
+    // Time out the receipts yielded by the contracts that would have timed out by `block_height`
+    // and execute their callbacks. Returns the number of receipts timed out.
+    async function time_out_yields(block_height) {
+        const contract = window.contract;
+        const count = contract.scheduler.time_out_yields(String(block_height));
         try {
-            await run(method);
+            await run_receipts(contract.scheduler, contract.config);
         } finally {
             update_ui();
         }
+        return count;
     }
 
     async function act_download_store() {
-        var blob = new Blob([window.contract.store.to_json()], { type: "application/json" });
+        var blob = new Blob([window.contract.world.to_json()], { type: "application/json" });
         var link = document.createElement('a');
         link.href = window.URL.createObjectURL(blob);
         link.download = `neardebug_${new Date().valueOf()}.nearstore`;
@@ -268,7 +341,7 @@ import init, { list_methods, prepare_contract, Logic, Context, Store, init_panic
         select.onchange = async (e) => {
             const file = e.target.files[0];
             const buffer = new Uint8Array(await file.arrayBuffer());
-            window.contract.store = Store.from_json(buffer);
+            window.contract.world = World.from_json(buffer, current_account_id());
             update_ui();
         };
         select.click();
@@ -278,7 +351,9 @@ import init, { list_methods, prepare_contract, Logic, Context, Store, init_panic
         await init();
         init_panic_hook();
         window.contract = {
-            store: new Store(),
+            world: new World(),
+            deploy: deploy,
+            time_out_yields: time_out_yields,
         };
         const form = document.querySelector('#contract_form');
         form.addEventListener('submit', async (e) => {
@@ -296,7 +371,9 @@ import init, { list_methods, prepare_contract, Logic, Context, Store, init_panic
         file_input.addEventListener('change', (e) => {
             on_contract_change(e.target);
         });
+        document.querySelector("#current_account").addEventListener("input", update_ui);
         on_contract_change(file_input);
+        document.querySelector("#protocol_version").placeholder = latest_protocol_version();
         block_timestamp_update();
         near_input_update();
         gas_input_update();
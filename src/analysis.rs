@@ -0,0 +1,187 @@
+//! Pre-flight report on whether a contract can be deployed and how much doing so costs.
+use crate::near_vm_runner::RuntimeConfig;
+use near_parameters::{ActionCosts, ExtCosts};
+use near_primitives_core::types::{Balance, Gas};
+use serde_with::{serde_as, DisplayFromStr};
+
+/// A quantity the runtime limits.
+#[derive(serde::Serialize)]
+pub(crate) struct Limited {
+    value: u64,
+    /// `None` if there is no limit.
+    limit: Option<u64>,
+    exceeded: bool,
+}
+
+impl Limited {
+    fn new(value: u64, limit: Option<u64>) -> Self {
+        Self {
+            value,
+            limit,
+            exceeded: limit.is_some_and(|limit| value > limit),
+        }
+    }
+}
+
+/// Gas of the `DeployContract` action deploying the contract.
+#[derive(serde::Serialize)]
+pub(crate) struct DeployCost {
+    /// Charged when the deploy is sent to the account itself, which is usually the case.
+    send_sir: Gas,
+    /// Charged when the deploy is sent to another account.
+    send_not_sir: Gas,
+    /// Charged when the deploy is executed.
+    exec: Gas,
+}
+
+/// Gas charged for loading the contract every time it is called.
+#[derive(serde::Serialize)]
+pub(crate) struct LoadingCost {
+    gas: Gas,
+    /// Whether the fees are charged before the contract is loaded, as opposed to after loading it
+    /// in the older protocol versions.
+    charged_before_loading: bool,
+}
+
+#[serde_as]
+#[derive(serde::Serialize)]
+pub(crate) struct ContractReport {
+    code_size: Limited,
+    /// Both imported and defined by the contract.
+    functions: Limited,
+    /// Declared by all of the functions together.
+    locals: Limited,
+    /// Why the contract cannot be prepared, if it can't.
+    prepare_error: Option<String>,
+    deploy_cost: DeployCost,
+    loading_cost: LoadingCost,
+    /// Balance the account must hold for storing the code.
+    #[serde_as(as = "DisplayFromStr")]
+    storage_stake: Balance,
+    /// Whether the runtime would refuse to deploy or run the contract.
+    pub(crate) rejected: bool,
+}
+
+/// Check the contract `code` against the limits of the `config` and compute the costs of
+/// deploying it.
+pub(crate) fn analyze(code: &[u8], config: &RuntimeConfig) -> Result<ContractReport, String> {
+    let wasm_config = config.wasm_config();
+    let limits = &wasm_config.limit_config;
+    let fees = config.fees();
+    let code_len = code.len() as u64;
+    let counts = crate::prepare::count_functions_and_locals(code).map_err(|e| e.to_string())?;
+
+    let base = fees.fee(ActionCosts::deploy_contract_base);
+    let per_byte = fees.fee(ActionCosts::deploy_contract_byte);
+    let total = |base: Gas, per_byte: Gas| base.saturating_add(per_byte.saturating_mul(code_len));
+    let deploy_cost = DeployCost {
+        send_sir: total(base.send_fee(true), per_byte.send_fee(true)),
+        send_not_sir: total(base.send_fee(false), per_byte.send_fee(false)),
+        exec: total(base.exec_fee(), per_byte.exec_fee()),
+    };
+    let loading_cost = LoadingCost {
+        gas: total(
+            ExtCosts::contract_loading_base.gas(&wasm_config.ext_costs),
+            ExtCosts::contract_loading_bytes.gas(&wasm_config.ext_costs),
+        ),
+        charged_before_loading: wasm_config.fix_contract_loading_cost,
+    };
+    let storage_amount_per_byte = fees.storage_usage_config.storage_amount_per_byte;
+
+    let code_size = Limited::new(code_len, Some(limits.max_contract_size));
+    let functions = Limited::new(counts.functions, limits.max_functions_number_per_contract);
+    let locals = Limited::new(counts.locals, limits.max_locals_per_contract);
+    let prepare_error = crate::prepare::prepare_contract(code, wasm_config)
+        .err()
+        .map(|e| e.to_string());
+    Ok(ContractReport {
+        rejected: code_size.exceeded
+            || functions.exceeded
+            || locals.exceeded
+            || prepare_error.is_some(),
+        code_size,
+        functions,
+        locals,
+        prepare_error,
+        deploy_cost,
+        loading_cost,
+        storage_stake: storage_amount_per_byte.saturating_mul(Balance::from(code_len)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_primitives_core::version::PROTOCOL_VERSION;
+
+    const CODE: &str = r#"(module
+        (import "env" "block_index" (func (result i64)))
+        (func (export "main") (param i32) (local i32 i64)))"#;
+
+    #[test]
+    fn test_report() {
+        let config = RuntimeConfig::new(PROTOCOL_VERSION);
+        let code = wat::parse_str(CODE).unwrap();
+        let len = code.len() as u64;
+        let report = analyze(&code, &config).unwrap();
+        let limits = &config.wasm_config().limit_config;
+        assert_eq!(report.code_size.value, len);
+        assert_eq!(report.code_size.limit, Some(limits.max_contract_size));
+        assert!(!report.code_size.exceeded);
+        assert_eq!(report.functions.value, 2);
+        assert_eq!(
+            report.functions.limit,
+            limits.max_functions_number_per_contract
+        );
+        assert_eq!(report.locals.value, 2);
+        assert_eq!(report.locals.limit, limits.max_locals_per_contract);
+        assert!(report.prepare_error.is_none());
+        assert!(!report.rejected);
+
+        let fees = config.fees();
+        let base = fees.fee(ActionCosts::deploy_contract_base);
+        let per_byte = fees.fee(ActionCosts::deploy_contract_byte);
+        assert_eq!(
+            report.deploy_cost.send_sir,
+            base.send_fee(true) + per_byte.send_fee(true) * len
+        );
+        assert_eq!(
+            report.deploy_cost.send_not_sir,
+            base.send_fee(false) + per_byte.send_fee(false) * len
+        );
+        assert_eq!(
+            report.deploy_cost.exec,
+            base.exec_fee() + per_byte.exec_fee() * len
+        );
+        let ext_costs = &config.wasm_config().ext_costs;
+        assert_eq!(
+            report.loading_cost.gas,
+            ExtCosts::contract_loading_base.gas(ext_costs)
+                + ExtCosts::contract_loading_bytes.gas(ext_costs) * len
+        );
+        assert_eq!(
+            report.storage_stake,
+            fees.storage_usage_config.storage_amount_per_byte * Balance::from(len)
+        );
+    }
+
+    #[test]
+    fn test_rejected_report() {
+        let config = RuntimeConfig::new(PROTOCOL_VERSION)
+            .apply_overrides(r#"{"wasm_config": {"limit_config": {"max_locals_per_contract": 1}}}"#)
+            .unwrap();
+        let report = analyze(&wat::parse_str(CODE).unwrap(), &config).unwrap();
+        assert!(report.locals.exceeded);
+        assert!(!report.functions.exceeded);
+        assert!(!report.code_size.exceeded);
+        let prepare_error = report.prepare_error.unwrap();
+        assert!(prepare_error.contains("too many locals"), "{prepare_error}");
+        assert!(report.rejected);
+    }
+
+    #[test]
+    fn test_invalid_code() {
+        let config = RuntimeConfig::new(PROTOCOL_VERSION);
+        assert!(analyze(b"not wasm", &config).is_err());
+    }
+}
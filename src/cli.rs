@@ -0,0 +1,406 @@
+//! The `neardebug` command line tool.
+//!
+//! Executes contracts natively, so that the problems found in the browser can be reproduced with a
+//! single command (e.g. in a bug report.)
+use crate::near_vm_runner::logic::types::PromiseResult;
+use crate::near_vm_runner::logic::VMContext;
+use crate::near_vm_runner::{
+    create_action_hash, native, parse_promise_results, serialize_public_key, verify_access_key,
+    ChainHashes, Context, DebugExternal, Logic, ReceiptScheduler, RuntimeConfig, World,
+    MIN_GAS_PRICE,
+};
+use base64::Engine as _;
+use near_crypto::PublicKey;
+use near_primitives_core::hash::CryptoHash;
+use near_primitives_core::types::{
+    AccountId, Balance, BlockHeight, EpochHeight, Gas, Nonce, ProtocolVersion,
+};
+use std::error::Error;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::Arc;
+
+#[derive(clap::Parser)]
+#[command(version, about = "A debugger for NEAR Protocol contracts")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(clap::Subcommand)]
+enum Command {
+    /// Execute a method of a contract, along with the receipts it produces.
+    ///
+    /// The logs are printed to stderr and the outcome of the call to stdout as JSON.
+    Run(RunArgs),
+    /// Check whether a contract can be deployed and report the costs of deploying it.
+    ///
+    /// The report is printed to stdout as JSON. Fails if the runtime would reject the contract.
+    Analyze(AnalyzeArgs),
+}
+
+#[derive(clap::Args)]
+struct AnalyzeArgs {
+    /// The contract to analyze.
+    wasm: PathBuf,
+    /// The protocol version to check the contract against. The latest one by default.
+    #[arg(long, default_value_t = near_primitives_core::version::PROTOCOL_VERSION)]
+    protocol_version: ProtocolVersion,
+    /// Runtime config parameters to override, as a JSON diff (see `RuntimeConfig::with_overrides`.)
+    #[arg(long)]
+    config_overrides: Option<String>,
+}
+
+#[derive(clap::Args)]
+struct RunArgs {
+    /// The contract to execute.
+    wasm: PathBuf,
+    /// The method to call.
+    method: String,
+    /// Arguments of the call, as JSON.
+    #[arg(long, conflicts_with = "args_base64")]
+    args: Option<String>,
+    /// Arguments of the call, base64 encoded.
+    #[arg(long)]
+    args_base64: Option<String>,
+    /// The `.nearstore` file with the state to execute the call against.
+    #[arg(long)]
+    store: Option<PathBuf>,
+    /// Write the state resulting from the call back to the `--store` file.
+    #[arg(long, requires = "store")]
+    write_store: bool,
+    /// The protocol version to execute the call with. The latest one by default.
+    #[arg(long, default_value_t = near_primitives_core::version::PROTOCOL_VERSION)]
+    protocol_version: ProtocolVersion,
+    /// Runtime config parameters to override, as a JSON diff (see `RuntimeConfig::with_overrides`.)
+    #[arg(long)]
+    config_overrides: Option<String>,
+    /// Print the host functions called by the contracts, along with their arguments and results.
+    #[arg(long)]
+    trace: bool,
+    /// Model the trie the contract storage is kept in, charging for the trie nodes touched and
+    /// enforcing the storage proof size limit like mainnet does.
+    #[arg(long)]
+    trie_model: bool,
+    /// Time out the receipts still waiting to be resumed once the others have been executed, and
+    /// execute their callbacks.
+    #[arg(long)]
+    time_out_yields: bool,
+    #[command(flatten)]
+    context: ContextArgs,
+    #[command(flatten)]
+    chain: ChainArgs,
+}
+
+/// The hashes of the chain the call is executed on (see the [`DebugExternal`] builder), zeroes by
+/// default, and its gas price.
+#[derive(clap::Args)]
+struct ChainArgs {
+    /// Hash of the action being executed, from which the ids of the receipts and data created by
+    /// the call are derived.
+    #[arg(long, conflicts_with = "receipt_id")]
+    action_hash: Option<CryptoHash>,
+    /// Derive the action hash from the id of the receipt being executed instead.
+    #[arg(long)]
+    receipt_id: Option<CryptoHash>,
+    /// Index of the function call action within the `--receipt-id` receipt.
+    #[arg(long, default_value_t = 0, requires = "receipt_id")]
+    action_index: usize,
+    #[arg(long)]
+    prev_block_hash: Option<CryptoHash>,
+    #[arg(long)]
+    block_hash: Option<CryptoHash>,
+    /// Random value of the block, from which the random seed is derived like nearcore does.
+    #[arg(long, conflicts_with = "random_seed")]
+    random_value: Option<CryptoHash>,
+    /// The gas price the cost of the call is charged to the allowance of the access key at.
+    #[arg(long, default_value_t = MIN_GAS_PRICE)]
+    gas_price: Balance,
+}
+
+impl ChainArgs {
+    fn chain_hashes(
+        &self,
+        protocol_version: ProtocolVersion,
+        block_height: BlockHeight,
+    ) -> ChainHashes {
+        let mut chain = ChainHashes {
+            action_hash: self.action_hash.unwrap_or_default(),
+            prev_block_hash: self.prev_block_hash.unwrap_or_default(),
+            block_hash: self.block_hash.unwrap_or_default(),
+            random_value: self.random_value,
+        };
+        if let Some(receipt_id) = &self.receipt_id {
+            chain.action_hash = create_action_hash(
+                protocol_version,
+                receipt_id,
+                &chain,
+                block_height,
+                self.action_index,
+            );
+        }
+        chain
+    }
+}
+
+/// The same knobs as the [`Context`] builder. Unspecified values default to those of
+/// [`Context::new`].
+#[derive(clap::Args)]
+struct ContextArgs {
+    #[arg(long)]
+    attached_deposit: Option<Balance>,
+    /// Replaces the balance of the account in the `--store`, if it is there already.
+    #[arg(long)]
+    balance: Option<Balance>,
+    /// Replaces the locked balance of the account in the `--store`, if it is there already.
+    #[arg(long)]
+    locked_balance: Option<Balance>,
+    #[arg(long)]
+    current_account: Option<AccountId>,
+    #[arg(long)]
+    signer_account: Option<AccountId>,
+    /// E.g. `ed25519:...` or `secp256k1:...`. If given, the signer account must have the key
+    /// in the `--store` and the call is checked against it.
+    #[arg(long)]
+    signer_account_pk: Option<PublicKey>,
+    /// Nonce of the transaction, the one following the nonce of the access key by default.
+    #[arg(long, requires = "signer_account_pk")]
+    nonce: Option<Nonce>,
+    #[arg(long)]
+    predecessor_account: Option<AccountId>,
+    #[arg(long)]
+    block_height: Option<BlockHeight>,
+    /// In nanoseconds.
+    #[arg(long)]
+    block_timestamp: Option<u64>,
+    #[arg(long)]
+    epoch_height: Option<EpochHeight>,
+    #[arg(long)]
+    random_seed: Option<CryptoHash>,
+    #[arg(long)]
+    gas: Option<Gas>,
+    /// Results of the promises the call is a callback of, as a JSON list (see
+    /// `Context::promise_results`.)
+    #[arg(long, value_parser = parse_promise_results)]
+    promise_results: Option<Arc<[PromiseResult]>>,
+    /// Execute the call as a view call, like the `call_function` RPC query would.
+    #[arg(long)]
+    view: bool,
+    /// The gas a view call may burn.
+    #[arg(long, default_value_t = 300_000_000_000_000, requires = "view")]
+    max_gas_burnt_view: Gas,
+}
+
+impl ContextArgs {
+    fn apply(self, mut context: Context) -> Context {
+        self.apply_values(context.vm_context_mut());
+        if self.view {
+            context = context.into_view(self.max_gas_burnt_view);
+        }
+        context
+    }
+
+    fn apply_values(&self, context: &mut VMContext) {
+        macro_rules! set {
+            ($($arg:ident => $field:ident),* $(,)?) => {
+                $(if let Some(value) = &self.$arg {
+                    context.$field = value.clone();
+                })*
+            };
+        }
+        set! {
+            attached_deposit => attached_deposit,
+            current_account => current_account_id,
+            signer_account => signer_account_id,
+            predecessor_account => predecessor_account_id,
+            block_height => block_height,
+            block_timestamp => block_timestamp,
+            epoch_height => epoch_height,
+            gas => prepaid_gas,
+            promise_results => promise_results,
+        }
+        if let Some(key) = &self.signer_account_pk {
+            context.signer_account_pk = serialize_public_key(key);
+        }
+        if let Some(seed) = &self.random_seed {
+            context.random_seed = seed.as_bytes().to_vec();
+        }
+    }
+}
+
+pub fn main() -> ExitCode {
+    let cli = <Cli as clap::Parser>::parse();
+    let result = match cli.command {
+        Command::Run(args) => run(args),
+        Command::Analyze(args) => analyze(args),
+    };
+    result.unwrap_or_else(|error| {
+        eprintln!("error: {error}");
+        ExitCode::FAILURE
+    })
+}
+
+fn run(args: RunArgs) -> Result<ExitCode, Box<dyn Error>> {
+    let code = std::fs::read(&args.wasm)
+        .map_err(|e| format!("could not read {}: {e}", args.wasm.display()))?;
+    // The calls are only checked against the access keys if the key is given.
+    let signer_key = args.context.signer_account_pk.is_some();
+    let nonce = args.context.nonce;
+    let balances = (args.context.balance, args.context.locked_balance);
+    let mut context = args.context.apply(Context::new());
+    let vm_context = context.vm_context_mut();
+    vm_context.input = match (args.args, args.args_base64) {
+        (Some(json), _) => {
+            serde_json::from_str::<serde_json::Value>(&json)
+                .map_err(|e| format!("--args is not valid JSON: {e}"))?;
+            json.into_bytes()
+        }
+        (None, Some(base64)) => base64::engine::general_purpose::STANDARD
+            .decode(base64)
+            .map_err(|e| format!("--args-base64 is not valid base64: {e}"))?,
+        (None, None) => vec![],
+    };
+    let account_id = vm_context.current_account_id.clone();
+
+    let world = match &args.store {
+        Some(path) => {
+            let bytes = std::fs::read(path)
+                .map_err(|e| format!("could not read {}: {e}", path.display()))?;
+            World::from_slice(&bytes, &account_id)?
+        }
+        None => World::new(),
+    };
+    if world.insert_account(&account_id, vm_context.account_balance) {
+        let locked = vm_context.account_locked_balance;
+        world.with_account(&account_id, |account| account.locked = locked);
+    }
+    world.with_account(&account_id, |account| {
+        let (balance, locked_balance) = balances;
+        account.amount = balance.unwrap_or(account.amount);
+        account.locked = locked_balance.unwrap_or(account.locked);
+        account.code = Some(code.as_slice().into());
+    });
+
+    let mut config = RuntimeConfig::new(args.protocol_version);
+    if let Some(overrides) = &args.config_overrides {
+        config = config.apply_overrides(overrides)?;
+    }
+    let with_trie_model = |ext: DebugExternal| {
+        if args.trie_model {
+            ext.with_trie_model()
+        } else {
+            ext
+        }
+    };
+    let chain = args
+        .chain
+        .chain_hashes(config.protocol_version(), vm_context.block_height);
+    world.release_unstaked(vm_context.epoch_height);
+    if signer_key {
+        let gas_price = args.chain.gas_price;
+        verify_access_key(
+            &world,
+            context.vm_context(),
+            &args.method,
+            nonce,
+            gas_price,
+            &config,
+        )
+        .map_err(|e| format!("the transaction is invalid: {e}"))?;
+    }
+    let ext = DebugExternal::with_chain_hashes(&world, &context, config, chain)?;
+    let ext = with_trie_model(ext);
+    let snapshot = world.with_account(&account_id, |account| account.snapshot());
+    let execute = if args.trace {
+        native::run_traced
+    } else {
+        native::run
+    };
+    let (logic, result) = execute(&code, &args.method, context, ext);
+    let outcome = logic.compute_outcome();
+    print_trace(&logic);
+    print_logs(&outcome.logs);
+
+    let mut scheduler = ReceiptScheduler::new(&world, &logic, result.is_ok());
+    let result = match (result, scheduler.error()) {
+        (Ok(()), Some(error)) => Err(error),
+        (result, _) => result.map_err(|e| e.to_string()),
+    };
+    if result.is_err() {
+        world.restore_account(&account_id, snapshot);
+    }
+    loop {
+        while let Some(call) = scheduler.next() {
+            eprintln!("--- {}: {}", call.receiver_id(), call.method_name());
+            let ext = match call.debug_external() {
+                Ok(ext) => with_trie_model(ext),
+                Err(error) => {
+                    scheduler.abort(error);
+                    continue;
+                }
+            };
+            let (logic, result) =
+                execute(call.code_bytes(), &call.method_name(), call.context(), ext);
+            print_trace(&logic);
+            print_logs(&logic.compute_outcome().logs);
+            scheduler.complete(&logic, result.err().map(|e| e.to_string()));
+        }
+        if !args.time_out_yields || scheduler.time_out_all_yields() == 0 {
+            break;
+        }
+        eprintln!("--- timing out the yielded receipts");
+    }
+    for outcome in scheduler.receipt_outcomes() {
+        if let Some(error) = &outcome.error {
+            eprintln!("receipt to {} failed: {error}", outcome.receiver_id);
+        }
+    }
+    if scheduler.pending_count() > 0 {
+        eprintln!(
+            "{} receipts are still waiting for data",
+            scheduler.pending_count()
+        );
+    }
+
+    println!("{}", serde_json::to_string_pretty(&outcome)?);
+    if args.write_store {
+        let path = args.store.as_ref().expect("clap requires --store");
+        std::fs::write(path, world.to_vec()?)
+            .map_err(|e| format!("could not write {}: {e}", path.display()))?;
+    }
+    match result {
+        Ok(()) => Ok(ExitCode::SUCCESS),
+        Err(error) => {
+            eprintln!("error: {error}");
+            Ok(ExitCode::FAILURE)
+        }
+    }
+}
+
+fn analyze(args: AnalyzeArgs) -> Result<ExitCode, Box<dyn Error>> {
+    let code = std::fs::read(&args.wasm)
+        .map_err(|e| format!("could not read {}: {e}", args.wasm.display()))?;
+    let mut config = RuntimeConfig::new(args.protocol_version);
+    if let Some(overrides) = &args.config_overrides {
+        config = config.apply_overrides(overrides)?;
+    }
+    let report = crate::analysis::analyze(&code, &config)?;
+    println!("{}", serde_json::to_string_pretty(&report)?);
+    Ok(if report.rejected {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    })
+}
+
+fn print_trace(logic: &Logic) {
+    for call in logic.tracer().calls() {
+        eprintln!("> {call}");
+    }
+}
+
+fn print_logs(logs: &[String]) {
+    for log in logs {
+        eprintln!("{log}");
+    }
+}
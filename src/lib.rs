@@ -1,8 +1,14 @@
+mod analysis;
+#[cfg(feature = "cli")]
+pub mod cli;
+mod listing;
 mod near_vm_runner;
 mod prepare;
 
-use finite_wasm::wasmparser::{self, Type};
-pub use near_vm_runner::{Context, Logic};
+#[cfg(feature = "native")]
+pub use near_vm_runner::native;
+pub use near_vm_runner::{Context, DebugExternal, Logic, RuntimeConfig, Store, World};
+use serde::Serialize as _;
 use wasm_bindgen::prelude::*;
 
 #[wasm_bindgen]
@@ -10,59 +16,37 @@ pub fn init_panic_hook() {
     console_error_panic_hook::set_once();
 }
 
+/// List the exports of the contract `wasm_bytes`, with their signature and whether they can be
+/// called as a method (and why not), along with its imports (and whether they are host functions.)
 #[wasm_bindgen]
-pub fn list_methods(wasm_bytes: &[u8]) -> Result<Vec<String>, JsError> {
-    let parser = wasmparser::Parser::new(0);
-    let mut types = vec![];
-    let mut fns = vec![];
-    let mut callable_methods = vec![];
-    for payload in parser.parse_all(wasm_bytes) {
-        match payload? {
-            wasmparser::Payload::ImportSection(ims) => {
-                for im in ims {
-                    let im = im?;
-                    match im.ty {
-                        wasmparser::TypeRef::Func(f) => fns.push(f),
-                        _ => {}
-                    }
-                }
-            }
-            wasmparser::Payload::TypeSection(type_section) => {
-                for ty in type_section {
-                    let ty = ty?;
-                    types.push(ty);
-                }
-            }
-            wasmparser::Payload::FunctionSection(function_section) => {
-                for (i, f) in function_section.into_iter().enumerate() {
-                    let f = f?;
-                    println!("{} {} {:?}", i, f, types[f as usize]);
-                    fns.push(f);
-                }
-            }
-            wasmparser::Payload::ExportSection(exports) => {
-                for export in exports {
-                    let ex = export?;
-                    let wasmparser::ExternalKind::Func = ex.kind else {
-                        continue;
-                    };
-                    let f = fns.get(ex.index as usize).copied();
-                    let Some(Type::Func(ty)) = f.and_then(|ty| types.get(ty as usize)) else {
-                        return Err(JsError::new("could not obtain function type for export"));
-                    };
-                    if ty.params().is_empty() && ty.results().is_empty() {
-                        callable_methods.push(ex.name.to_string());
-                    }
-                }
-                return Ok(callable_methods);
-            }
-            _ => {}
-        }
-    }
-    Ok(callable_methods)
+pub fn list_methods(wasm_bytes: &[u8]) -> Result<JsValue, JsError> {
+    let listing = listing::list(wasm_bytes).map_err(|e| JsError::new(&e))?;
+    Ok(listing.serialize(&near_vm_runner::js_serializer())?)
 }
 
+/// The most recent protocol version the debugger knows about.
 #[wasm_bindgen]
-pub fn prepare_contract(wasm_bytes: &[u8]) -> Result<Vec<u8>, JsError> {
-    prepare::prepare_contract(wasm_bytes)
+pub fn latest_protocol_version() -> near_primitives_core::types::ProtocolVersion {
+    near_primitives_core::version::PROTOCOL_VERSION
+}
+
+/// Prepare the contract with the limits and wasm features of the runtime `config`.
+///
+/// Throws an error with the `PrepareError` nearcore would report in its `function_call_error`
+/// property, see [`Logic::execute`].
+#[wasm_bindgen]
+pub fn prepare_contract(wasm_bytes: &[u8], config: &RuntimeConfig) -> Result<Vec<u8>, JsValue> {
+    prepare::prepare_contract(wasm_bytes, config.wasm_config())
+        .map_err(|e| near_vm_runner::prepare_error_to_js(&e))
+}
+
+/// Check the contract `wasm_bytes` against the limits of the runtime `config` before deploying it.
+///
+/// Reports the code size, the number of functions and locals along with their limits, the error
+/// preparing the contract fails with (if any), the gas of the `DeployContract` action, the gas
+/// charged for loading the contract on every call and the balance staked for storing the code.
+#[wasm_bindgen]
+pub fn analyze_contract(wasm_bytes: &[u8], config: &RuntimeConfig) -> Result<JsValue, JsError> {
+    let report = analysis::analyze(wasm_bytes, config).map_err(|e| JsError::new(&e))?;
+    Ok(report.serialize(&near_vm_runner::js_serializer())?)
 }
@@ -0,0 +1,160 @@
+//! The functions a contract exports and imports, as far as the runtime is concerned.
+use crate::near_vm_runner::is_host_function;
+use finite_wasm::wasmparser::{self, Type};
+
+/// Why an export cannot be called by the runtime.
+#[derive(Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum NotCallable {
+    /// The export is a memory, table or such.
+    NotAFunction,
+    /// The function takes parameters, while methods are called without any.
+    HasParams,
+    /// The function returns results, while methods must not return anything.
+    HasResults,
+}
+
+#[derive(serde::Serialize)]
+pub(crate) struct Signature {
+    params: Vec<&'static str>,
+    results: Vec<&'static str>,
+}
+
+#[derive(serde::Serialize)]
+pub(crate) struct Export {
+    pub(crate) name: String,
+    kind: &'static str,
+    /// `None` unless the export is a function.
+    signature: Option<Signature>,
+    /// Whether the export can be called as a method of the contract.
+    pub(crate) callable: bool,
+    pub(crate) not_callable: Vec<NotCallable>,
+}
+
+#[derive(serde::Serialize)]
+pub(crate) struct Import {
+    module: String,
+    name: String,
+    kind: &'static str,
+    signature: Option<Signature>,
+    /// Whether the import is not one of the host functions, in which case the contract cannot be
+    /// instantiated.
+    unknown: bool,
+}
+
+#[derive(serde::Serialize)]
+pub(crate) struct ContractListing {
+    pub(crate) exports: Vec<Export>,
+    imports: Vec<Import>,
+}
+
+fn value_type(ty: &wasmparser::ValType) -> &'static str {
+    match ty {
+        wasmparser::ValType::I32 => "i32",
+        wasmparser::ValType::I64 => "i64",
+        wasmparser::ValType::F32 => "f32",
+        wasmparser::ValType::F64 => "f64",
+        wasmparser::ValType::V128 => "v128",
+        _ => "ref",
+    }
+}
+
+fn signature(types: &[Type], type_index: u32) -> Result<Signature, String> {
+    let Some(Type::Func(ty)) = types.get(type_index as usize) else {
+        return Err(format!("function type {type_index} does not exist"));
+    };
+    Ok(Signature {
+        params: ty.params().iter().map(value_type).collect(),
+        results: ty.results().iter().map(value_type).collect(),
+    })
+}
+
+/// List the exports and imports of the contract `wasm`.
+pub(crate) fn list(wasm: &[u8]) -> Result<ContractListing, String> {
+    let parser = wasmparser::Parser::new(0);
+    let mut types = vec![];
+    let mut functions = vec![];
+    let mut listing = ContractListing {
+        exports: vec![],
+        imports: vec![],
+    };
+    for payload in parser.parse_all(wasm) {
+        match payload.map_err(|e| format!("could not parse webassembly: {e}"))? {
+            wasmparser::Payload::TypeSection(reader) => {
+                for ty in reader {
+                    types.push(ty.map_err(|e| format!("could not parse a type: {e}"))?);
+                }
+            }
+            wasmparser::Payload::ImportSection(reader) => {
+                for import in reader {
+                    let import = import.map_err(|e| format!("could not parse an import: {e}"))?;
+                    let (kind, signature) = match import.ty {
+                        wasmparser::TypeRef::Func(ty) => {
+                            functions.push(ty);
+                            ("function", Some(signature(&types, ty)?))
+                        }
+                        wasmparser::TypeRef::Table(_) => ("table", None),
+                        wasmparser::TypeRef::Memory(_) => ("memory", None),
+                        wasmparser::TypeRef::Global(_) => ("global", None),
+                        wasmparser::TypeRef::Tag(_) => ("tag", None),
+                    };
+                    listing.imports.push(Import {
+                        unknown: import.module != "env" || !is_host_function(import.name),
+                        module: import.module.to_string(),
+                        name: import.name.to_string(),
+                        kind,
+                        signature,
+                    });
+                }
+            }
+            wasmparser::Payload::FunctionSection(reader) => {
+                for function in reader {
+                    functions
+                        .push(function.map_err(|e| format!("could not parse a function: {e}"))?);
+                }
+            }
+            wasmparser::Payload::ExportSection(reader) => {
+                for export in reader {
+                    let export = export.map_err(|e| format!("could not parse an export: {e}"))?;
+                    let kind = match export.kind {
+                        wasmparser::ExternalKind::Func => "function",
+                        wasmparser::ExternalKind::Table => "table",
+                        wasmparser::ExternalKind::Memory => "memory",
+                        wasmparser::ExternalKind::Global => "global",
+                        wasmparser::ExternalKind::Tag => "tag",
+                    };
+                    let signature = match export.kind {
+                        wasmparser::ExternalKind::Func => {
+                            let Some(&ty) = functions.get(export.index as usize) else {
+                                return Err(format!("function {} does not exist", export.index));
+                            };
+                            Some(signature(&types, ty)?)
+                        }
+                        _ => None,
+                    };
+                    let mut not_callable = vec![];
+                    match &signature {
+                        None => not_callable.push(NotCallable::NotAFunction),
+                        Some(signature) => {
+                            if !signature.params.is_empty() {
+                                not_callable.push(NotCallable::HasParams);
+                            }
+                            if !signature.results.is_empty() {
+                                not_callable.push(NotCallable::HasResults);
+                            }
+                        }
+                    }
+                    listing.exports.push(Export {
+                        name: export.name.to_string(),
+                        kind,
+                        signature,
+                        callable: not_callable.is_empty(),
+                        not_callable,
+                    });
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(listing)
+}
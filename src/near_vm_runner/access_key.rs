@@ -0,0 +1,447 @@
+//! The checks nearcore makes against the access key a transaction calling a contract is signed
+//! with, before the call is executed.
+use super::logic::VMContext;
+use super::world::AccessKeyPermission;
+use super::{Context, Result, RuntimeConfig, World};
+use near_crypto::PublicKey;
+use near_parameters::ActionCosts;
+use near_primitives_core::types::{AccountId, Balance, Gas, Nonce};
+use wasm_bindgen::prelude::*;
+
+/// The minimum gas price of mainnet, for the calls that do not specify the gas price.
+pub(crate) const MIN_GAS_PRICE: Balance = 100_000_000;
+
+/// The call is not permitted by the access key it is signed with. Mirrors the
+/// `InvalidAccessKeyError` (or for the nonce, the `InvalidTxError`) nearcore rejects the
+/// transaction with.
+#[derive(Clone, Debug)]
+pub(crate) enum InvalidAccessKeyError {
+    /// The signer public key of the context is not a borsh serialized `PublicKey`, so nearcore
+    /// could not even decode the transaction.
+    InvalidPublicKey,
+    AccessKeyNotFound {
+        account_id: AccountId,
+        public_key: PublicKey,
+    },
+    InvalidNonce {
+        tx_nonce: Nonce,
+        ak_nonce: Nonce,
+    },
+    ReceiverMismatch {
+        tx_receiver: AccountId,
+        ak_receiver: AccountId,
+    },
+    MethodNameMismatch {
+        method_name: String,
+    },
+    NotEnoughAllowance {
+        account_id: AccountId,
+        public_key: PublicKey,
+        allowance: Balance,
+        cost: Balance,
+    },
+    DepositWithFunctionCall,
+}
+
+impl std::fmt::Display for InvalidAccessKeyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidPublicKey => write!(f, "The signer public key is not a valid public key"),
+            Self::AccessKeyNotFound {
+                account_id,
+                public_key,
+            } => write!(
+                f,
+                "Signer {account_id:?} doesn't have access key with the given public_key \
+                 {public_key}"
+            ),
+            Self::InvalidNonce { tx_nonce, ak_nonce } => write!(
+                f,
+                "Transaction nonce {tx_nonce} must be larger than nonce of the used access key \
+                 {ak_nonce}"
+            ),
+            Self::ReceiverMismatch {
+                tx_receiver,
+                ak_receiver,
+            } => write!(
+                f,
+                "Transaction receiver_id {tx_receiver:?} doesn't match the access key receiver_id \
+                 {ak_receiver:?}"
+            ),
+            Self::MethodNameMismatch { method_name } => write!(
+                f,
+                "Transaction method name {method_name:?} isn't allowed by the access key"
+            ),
+            Self::NotEnoughAllowance {
+                account_id,
+                public_key,
+                allowance,
+                cost,
+            } => write!(
+                f,
+                "Access Key {account_id:?}:{public_key} does not have enough balance {allowance} \
+                 for transaction costing {cost}"
+            ),
+            Self::DepositWithFunctionCall => write!(
+                f,
+                "Having a deposit with a function call action is not allowed with a function \
+                 call access key."
+            ),
+        }
+    }
+}
+
+/// Cost of the transaction calling `method_name` in the `context` at the `gas_price`, as charged to
+/// the allowance: the fees of sending and executing the function call and the prepaid gas.
+fn transaction_cost(
+    context: &VMContext,
+    method_name: &str,
+    gas_price: Balance,
+    config: &RuntimeConfig,
+) -> Balance {
+    let fees = config.fees();
+    let sir = context.signer_account_id == context.current_account_id;
+    let num_bytes = (method_name.len() + context.input.len()) as u64;
+    let fee = |cost: ActionCosts| {
+        let fee = fees.fee(cost);
+        fee.send_fee(sir).saturating_add(fee.exec_fee())
+    };
+    let gas: Gas = fee(ActionCosts::new_action_receipt)
+        .saturating_add(fee(ActionCosts::function_call_base))
+        .saturating_add(fee(ActionCosts::function_call_byte).saturating_mul(num_bytes))
+        .saturating_add(context.prepaid_gas);
+    Balance::from(gas)
+        .saturating_mul(gas_price)
+        .saturating_add(context.attached_deposit)
+}
+
+/// Check that the access key of the signer permits the call of `method_name` in the `context`,
+/// and charge its allowance for the call at the `gas_price` like nearcore does.
+///
+/// The nonce of the key is bumped to the `nonce` of the transaction, which has to be larger than
+/// that of the key. Without a `nonce` the transaction uses the nonce following that of the key.
+/// View calls are not checked.
+pub(crate) fn verify_access_key(
+    world: &World,
+    context: &VMContext,
+    method_name: &str,
+    nonce: Option<Nonce>,
+    gas_price: Balance,
+    config: &RuntimeConfig,
+) -> std::result::Result<(), InvalidAccessKeyError> {
+    if context.view_config.is_some() {
+        return Ok(());
+    }
+    let public_key = borsh::from_slice::<PublicKey>(&context.signer_account_pk)
+        .map_err(|_| InvalidAccessKeyError::InvalidPublicKey)?;
+    let signer_id = &context.signer_account_id;
+    let not_found = || InvalidAccessKeyError::AccessKeyNotFound {
+        account_id: signer_id.clone(),
+        public_key: public_key.clone(),
+    };
+    let cost = transaction_cost(context, method_name, gas_price, config);
+    world
+        .with_account(signer_id, |account| {
+            let key = account
+                .access_keys
+                .get_mut(&public_key)
+                .ok_or_else(not_found)?;
+            let tx_nonce = nonce.unwrap_or(key.nonce.saturating_add(1));
+            if tx_nonce <= key.nonce {
+                return Err(InvalidAccessKeyError::InvalidNonce {
+                    tx_nonce,
+                    ak_nonce: key.nonce,
+                });
+            }
+            let AccessKeyPermission::FunctionCall {
+                allowance,
+                receiver_id,
+                method_names,
+            } = &mut key.permission
+            else {
+                key.nonce = tx_nonce;
+                return Ok(());
+            };
+            let remaining = allowance
+                .map(|allowance| {
+                    allowance.checked_sub(cost).ok_or_else(|| {
+                        InvalidAccessKeyError::NotEnoughAllowance {
+                            account_id: signer_id.clone(),
+                            public_key: public_key.clone(),
+                            allowance,
+                            cost,
+                        }
+                    })
+                })
+                .transpose()?;
+            if context.attached_deposit > 0 {
+                return Err(InvalidAccessKeyError::DepositWithFunctionCall);
+            }
+            if *receiver_id != context.current_account_id {
+                return Err(InvalidAccessKeyError::ReceiverMismatch {
+                    tx_receiver: context.current_account_id.clone(),
+                    ak_receiver: receiver_id.clone(),
+                });
+            }
+            if !method_names.is_empty() && !method_names.iter().any(|name| name == method_name) {
+                return Err(InvalidAccessKeyError::MethodNameMismatch {
+                    method_name: method_name.to_string(),
+                });
+            }
+            *allowance = remaining;
+            key.nonce = tx_nonce;
+            Ok(())
+        })
+        .unwrap_or_else(|| Err(not_found()))
+}
+
+#[wasm_bindgen]
+impl World {
+    /// Check that the access key the `context` is signed with permits calling the `method_name`,
+    /// the way nearcore checks the transactions before converting them to receipts, charge the
+    /// allowance of the key for the call at the `gas_price` (the minimum gas price of mainnet if
+    /// not given) and bump the nonce of the key to the `nonce` of the transaction (the one
+    /// following that of the key if not given).
+    ///
+    /// Fails if the signer account does not have the key, so calls that should not be checked
+    /// must not call this.
+    pub fn verify_signer_access_key(
+        &self,
+        context: &Context,
+        method_name: &str,
+        nonce: Option<String>,
+        gas_price: Option<String>,
+        config: &RuntimeConfig,
+    ) -> Result<()> {
+        let nonce = nonce.map(|nonce| nonce.parse()).transpose()?;
+        let gas_price = gas_price
+            .map(|gas_price| gas_price.parse())
+            .transpose()?
+            .unwrap_or(MIN_GAS_PRICE);
+        verify_access_key(self, &context.0, method_name, nonce, gas_price, config)
+            .map_err(|e| JsError::new(&e.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::near_vm_runner::serialize_public_key;
+    use crate::near_vm_runner::world::AccessKey;
+    use near_crypto::KeyType;
+    use near_primitives_core::version::PROTOCOL_VERSION;
+
+    const ALLOWANCE: Balance = 100_000_000_000_000_000_000_000;
+
+    fn bob() -> AccountId {
+        "bob.near".parse().unwrap()
+    }
+
+    fn public_key() -> PublicKey {
+        PublicKey::empty(KeyType::ED25519)
+    }
+
+    /// A world where bob.near has the key `Context::new` is signed with, at nonce 5.
+    fn world(permission: AccessKeyPermission) -> World {
+        let world = World::new();
+        world.insert_account(&bob(), ALLOWANCE);
+        world.with_account(&bob(), |account| {
+            let key = AccessKey {
+                nonce: 5,
+                permission,
+            };
+            account.access_keys.insert(public_key(), key)
+        });
+        world
+    }
+
+    fn function_call(allowance: Option<Balance>, method_names: &[&str]) -> AccessKeyPermission {
+        AccessKeyPermission::FunctionCall {
+            allowance,
+            receiver_id: "alice.near".parse().unwrap(),
+            method_names: method_names.iter().map(|name| name.to_string()).collect(),
+        }
+    }
+
+    fn key(world: &World) -> AccessKey {
+        world
+            .with_account(&bob(), |account| account.access_keys[&public_key()].clone())
+            .unwrap()
+    }
+
+    fn allowance(world: &World) -> Option<Balance> {
+        match key(world).permission {
+            AccessKeyPermission::FunctionCall { allowance, .. } => allowance,
+            AccessKeyPermission::FullAccess => panic!("not a function call key"),
+        }
+    }
+
+    fn verify(
+        world: &World,
+        context: &Context,
+        method_name: &str,
+        nonce: Option<Nonce>,
+    ) -> std::result::Result<(), InvalidAccessKeyError> {
+        let config = RuntimeConfig::new(PROTOCOL_VERSION);
+        verify_access_key(
+            world,
+            context.vm_context(),
+            method_name,
+            nonce,
+            MIN_GAS_PRICE,
+            &config,
+        )
+    }
+
+    #[test]
+    fn test_nonce_must_increase() {
+        let world = world(AccessKeyPermission::FullAccess);
+        let context = Context::new();
+        let result = verify(&world, &context, "method", Some(5));
+        assert!(matches!(
+            result,
+            Err(InvalidAccessKeyError::InvalidNonce {
+                tx_nonce: 5,
+                ak_nonce: 5
+            })
+        ));
+        assert_eq!(key(&world).nonce, 5);
+        verify(&world, &context, "method", Some(7)).unwrap();
+        assert_eq!(key(&world).nonce, 7);
+        let result = verify(&world, &context, "method", Some(6));
+        assert!(matches!(
+            result,
+            Err(InvalidAccessKeyError::InvalidNonce {
+                tx_nonce: 6,
+                ak_nonce: 7
+            })
+        ));
+        verify(&world, &context, "method", None).unwrap();
+        assert_eq!(key(&world).nonce, 8);
+    }
+
+    #[test]
+    fn test_full_access_key_is_not_restricted() {
+        let world = world(AccessKeyPermission::FullAccess);
+        let mut context = Context::new();
+        context.vm_context_mut().current_account_id = "carol.near".parse().unwrap();
+        context.vm_context_mut().attached_deposit = ALLOWANCE;
+        verify(&world, &context, "method", None).unwrap();
+        let key = key(&world);
+        assert_eq!(key.nonce, 6);
+        assert!(matches!(key.permission, AccessKeyPermission::FullAccess));
+    }
+
+    #[test]
+    fn test_allowance_is_charged() {
+        let world = world(function_call(Some(ALLOWANCE), &[]));
+        let mut context = Context::new();
+        context.vm_context_mut().input = b"{}".to_vec();
+        let config = RuntimeConfig::new(PROTOCOL_VERSION);
+        let cost = transaction_cost(context.vm_context(), "method", MIN_GAS_PRICE, &config);
+        // The prepaid gas is paid for on top of the fees.
+        let prepaid = Balance::from(context.vm_context().prepaid_gas) * MIN_GAS_PRICE;
+        assert!(cost > prepaid);
+        verify(&world, &context, "method", None).unwrap();
+        assert_eq!(allowance(&world), Some(ALLOWANCE - cost));
+        assert_eq!(key(&world).nonce, 6);
+        verify(&world, &context, "method", None).unwrap();
+        assert_eq!(allowance(&world), Some(ALLOWANCE - 2 * cost));
+        assert_eq!(key(&world).nonce, 7);
+    }
+
+    #[test]
+    fn test_not_enough_allowance() {
+        let world = world(function_call(Some(1), &[]));
+        let context = Context::new();
+        let result = verify(&world, &context, "method", None);
+        assert!(matches!(
+            result,
+            Err(InvalidAccessKeyError::NotEnoughAllowance { allowance: 1, .. })
+        ));
+        assert_eq!(allowance(&world), Some(1));
+        assert_eq!(key(&world).nonce, 5);
+    }
+
+    #[test]
+    fn test_unlimited_allowance() {
+        let world = world(function_call(None, &[]));
+        verify(&world, &Context::new(), "method", None).unwrap();
+        assert_eq!(allowance(&world), None);
+        assert_eq!(key(&world).nonce, 6);
+    }
+
+    #[test]
+    fn test_receiver_must_match() {
+        let world = world(function_call(Some(ALLOWANCE), &[]));
+        let mut context = Context::new();
+        context.vm_context_mut().current_account_id = "carol.near".parse().unwrap();
+        let result = verify(&world, &context, "method", None);
+        assert!(matches!(
+            result,
+            Err(InvalidAccessKeyError::ReceiverMismatch { .. })
+        ));
+        assert_eq!(allowance(&world), Some(ALLOWANCE));
+        assert_eq!(key(&world).nonce, 5);
+    }
+
+    #[test]
+    fn test_method_name_must_be_allowed() {
+        let world = world(function_call(Some(ALLOWANCE), &["one", "two"]));
+        let context = Context::new();
+        let result = verify(&world, &context, "three", None);
+        assert!(matches!(
+            result,
+            Err(InvalidAccessKeyError::MethodNameMismatch { method_name }) if method_name == "three"
+        ));
+        assert_eq!(key(&world).nonce, 5);
+        verify(&world, &context, "two", None).unwrap();
+        assert_eq!(key(&world).nonce, 6);
+    }
+
+    #[test]
+    fn test_deposit_with_function_call_key() {
+        let world = world(function_call(Some(ALLOWANCE), &[]));
+        let mut context = Context::new();
+        context.vm_context_mut().attached_deposit = 1;
+        let result = verify(&world, &context, "method", None);
+        assert!(matches!(
+            result,
+            Err(InvalidAccessKeyError::DepositWithFunctionCall)
+        ));
+        assert_eq!(key(&world).nonce, 5);
+    }
+
+    #[test]
+    fn test_missing_or_invalid_key() {
+        let world = world(AccessKeyPermission::FullAccess);
+        let mut context = Context::new();
+        let other = PublicKey::empty(KeyType::SECP256K1);
+        context.vm_context_mut().signer_account_pk = serialize_public_key(&other);
+        let result = verify(&world, &context, "method", None);
+        assert!(matches!(
+            result,
+            Err(InvalidAccessKeyError::AccessKeyNotFound { .. })
+        ));
+        context.vm_context_mut().signer_account_pk = vec![7; 3];
+        let result = verify(&world, &context, "method", None);
+        assert!(matches!(
+            result,
+            Err(InvalidAccessKeyError::InvalidPublicKey)
+        ));
+        let mut context = Context::new();
+        context.vm_context_mut().signer_account_id = "carol.near".parse().unwrap();
+        let result = verify(&world, &context, "method", None);
+        assert!(matches!(
+            result,
+            Err(InvalidAccessKeyError::AccessKeyNotFound { .. })
+        ));
+    }
+
+    #[test]
+    fn test_view_call_is_not_checked() {
+        let world = World::new();
+        let context = Context::new().into_view(300_000_000_000_000);
+        verify(&world, &context, "method", None).unwrap();
+    }
+}
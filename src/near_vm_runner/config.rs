@@ -0,0 +1,209 @@
+//! Runtime configuration the contracts are prepared and executed with.
+use super::{js_serializer, Result};
+use near_parameters::vm;
+use near_parameters::RuntimeFeesConfig;
+use near_primitives_core::hash::CryptoHash;
+use near_primitives_core::types::ProtocolVersion;
+use serde::Serialize as _;
+use serde_json::Value;
+use std::sync::Arc;
+use wasm_bindgen::prelude::*;
+
+/// The fees, limits and wasm features in effect at a protocol version, possibly with some of the
+/// parameters overridden.
+#[wasm_bindgen]
+#[derive(Clone)]
+pub struct RuntimeConfig {
+    protocol_version: ProtocolVersion,
+    config: Arc<near_parameters::RuntimeConfig>,
+}
+
+#[derive(serde::Serialize)]
+struct ConfigView<'a> {
+    fees: &'a RuntimeFeesConfig,
+    wasm_config: &'a vm::Config,
+}
+
+#[wasm_bindgen]
+impl RuntimeConfig {
+    #[wasm_bindgen(constructor)]
+    pub fn new(protocol_version: ProtocolVersion) -> Self {
+        let config_store = near_parameters::RuntimeConfigStore::new(None);
+        Self {
+            protocol_version,
+            config: config_store.get_config(protocol_version).clone(),
+        }
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn protocol_version(&self) -> ProtocolVersion {
+        self.protocol_version
+    }
+
+    /// Override some of the parameters.
+    ///
+    /// The `diff` is a JSON object with the same structure as the output of [`Self::to_json`],
+    /// containing only the parameters to change. For instance
+    /// `{"wasm_config": {"ext_costs": {"costs": {"storage_write_base": {"gas": 0, "compute": 0}}}}}`
+    pub fn with_overrides(&self, diff: &str) -> Result<RuntimeConfig> {
+        self.apply_overrides(diff).map_err(|e| JsError::new(&e))
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        serde_json::to_string_pretty(&self.view()).map_err(Into::into)
+    }
+
+    pub fn to_value(&self) -> Result<JsValue> {
+        self.view().serialize(&js_serializer()).map_err(Into::into)
+    }
+
+    /// Identifies the parameters that affect contract preparation. Contracts prepared with
+    /// configs that have the same hash are interchangeable.
+    pub fn wasm_config_hash(&self) -> Result<String> {
+        let json = serde_json::to_vec(&*self.config.wasm_config)?;
+        Ok(CryptoHash::hash_bytes(&json).to_string())
+    }
+}
+
+impl RuntimeConfig {
+    fn view(&self) -> ConfigView<'_> {
+        ConfigView {
+            fees: &self.config.fees,
+            wasm_config: &self.config.wasm_config,
+        }
+    }
+
+    /// See [`Self::with_overrides`].
+    pub(crate) fn apply_overrides(&self, diff: &str) -> std::result::Result<Self, String> {
+        let diff = serde_json::from_str(diff).map_err(|e| e.to_string())?;
+        let Value::Object(mut diff) = diff else {
+            return Err("runtime config overrides must be a JSON object".into());
+        };
+        let mut config = near_parameters::RuntimeConfig::clone(&self.config);
+        if let Some(fees) = diff.remove("fees") {
+            config.fees = Arc::new(apply_diff(&*config.fees, fees, "fees")?);
+        }
+        if let Some(wasm_config) = diff.remove("wasm_config") {
+            config.wasm_config = Arc::new(apply_diff(
+                &*config.wasm_config,
+                wasm_config,
+                "wasm_config",
+            )?);
+        }
+        if let Some(key) = diff.keys().next() {
+            return Err(format!("unknown runtime config parameter `{key}`"));
+        }
+        Ok(Self {
+            protocol_version: self.protocol_version,
+            config: Arc::new(config),
+        })
+    }
+
+    pub(crate) fn fees(&self) -> &Arc<RuntimeFeesConfig> {
+        &self.config.fees
+    }
+
+    pub(crate) fn wasm_config(&self) -> &Arc<vm::Config> {
+        &self.config.wasm_config
+    }
+}
+
+/// Override the parameters of `value` that are present in the JSON `diff`.
+fn apply_diff<T>(value: &T, diff: Value, path: &str) -> std::result::Result<T, String>
+where
+    T: serde::Serialize + serde::de::DeserializeOwned,
+{
+    let mut json = serde_json::to_value(value).map_err(|e| e.to_string())?;
+    merge(&mut json, diff, path)?;
+    serde_json::from_value(json).map_err(|e| format!("invalid `{path}` overrides: {e}"))
+}
+
+fn merge(target: &mut Value, diff: Value, path: &str) -> std::result::Result<(), String> {
+    match (target, diff) {
+        (Value::Object(target), Value::Object(diff)) => {
+            for (key, value) in diff {
+                let path = format!("{path}.{key}");
+                let Some(target) = target.get_mut(&key) else {
+                    return Err(format!("unknown runtime config parameter `{path}`"));
+                };
+                merge(target, value, &path)?;
+            }
+        }
+        (target, diff) => *target = diff,
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_parameters::ExtCosts;
+    use near_primitives_core::version::PROTOCOL_VERSION;
+
+    #[test]
+    fn test_overrides_merge_nested_parameters() {
+        let config = RuntimeConfig::new(PROTOCOL_VERSION);
+        let overridden = config
+            .apply_overrides(
+                r#"{
+                    "fees": {"storage_usage_config": {"num_bytes_account": 200}},
+                    "wasm_config": {
+                        "ext_costs": {"costs": {"storage_write_base": {"gas": 0, "compute": 0}}},
+                        "limit_config": {"max_locals_per_contract": 7}
+                    }
+                }"#,
+            )
+            .unwrap();
+        assert_eq!(overridden.protocol_version(), PROTOCOL_VERSION);
+
+        let storage_usage = &overridden.fees().storage_usage_config;
+        assert_eq!(storage_usage.num_bytes_account, 200);
+        assert_eq!(
+            storage_usage.num_extra_bytes_record,
+            config.fees().storage_usage_config.num_extra_bytes_record
+        );
+
+        let ext_costs = &overridden.wasm_config().ext_costs;
+        let original = &config.wasm_config().ext_costs;
+        assert_eq!(ExtCosts::storage_write_base.gas(ext_costs), 0);
+        assert_ne!(ExtCosts::storage_write_base.gas(original), 0);
+        assert_eq!(
+            ExtCosts::storage_write_key_byte.gas(ext_costs),
+            ExtCosts::storage_write_key_byte.gas(original)
+        );
+
+        let limits = &overridden.wasm_config().limit_config;
+        let original = &config.wasm_config().limit_config;
+        assert_eq!(limits.max_locals_per_contract, Some(7));
+        assert_eq!(limits.max_contract_size, original.max_contract_size);
+        // The config overridden is left as it was.
+        assert_ne!(original.max_locals_per_contract, Some(7));
+    }
+
+    #[test]
+    fn test_overrides_reject_unknown_parameters() {
+        let config = RuntimeConfig::new(PROTOCOL_VERSION);
+        let error = |diff: &str| config.apply_overrides(diff).err().unwrap();
+        assert_eq!(
+            error(r#"{"wasm_config": {}, "gas": 1}"#),
+            "unknown runtime config parameter `gas`"
+        );
+        assert_eq!(
+            error(r#"{"wasm_config": {"limit_config": {"max_lokals_per_contract": 1}}}"#),
+            "unknown runtime config parameter `wasm_config.limit_config.max_lokals_per_contract`"
+        );
+        assert_eq!(
+            error(r#"{"fees": {"storage_usage_config": {"num_bytes": 1}}}"#),
+            "unknown runtime config parameter `fees.storage_usage_config.num_bytes`"
+        );
+        assert_eq!(
+            error("[]"),
+            "runtime config overrides must be a JSON object"
+        );
+        let invalid = error(r#"{"wasm_config": {"limit_config": {"max_contract_size": "big"}}}"#);
+        assert!(
+            invalid.starts_with("invalid `wasm_config` overrides"),
+            "{invalid}"
+        );
+    }
+}
@@ -0,0 +1,100 @@
+//! Execution of a contract method, following the same sequence of steps nearcore does: charging
+//! the fees for loading the contract, preparing and instantiating it, resolving the method and
+//! finally calling it.
+//!
+//! A failure at any of the steps is recorded as the `aborted` error of the outcome.
+use super::logic::errors::{CompilationError, FunctionCallError, MethodResolveError};
+use super::{function_call_error_to_js, js_error_message, prepare_error_to_js, Logic};
+use crate::listing::{self, NotCallable};
+use js_sys::Uint8Array;
+use std::result::Result as SResult;
+use wasm_bindgen::prelude::*;
+
+/// Check that the contract `code` exports a `method_name` function the runtime can call, i.e. one
+/// that takes no arguments and returns nothing.
+pub(crate) fn resolve_method(code: &[u8], method_name: &str) -> SResult<(), MethodResolveError> {
+    let listing = listing::list(code).map_err(|_| MethodResolveError::MethodNotFound)?;
+    match listing
+        .exports
+        .iter()
+        .find(|export| export.name == method_name)
+    {
+        Some(export) if export.callable => Ok(()),
+        Some(export) if !export.not_callable.contains(&NotCallable::NotAFunction) => {
+            Err(MethodResolveError::MethodInvalidSignature)
+        }
+        _ => Err(MethodResolveError::MethodNotFound),
+    }
+}
+
+/// A contract that has been prepared but still could not be compiled by the engine.
+pub(crate) fn compile_error(msg: String) -> FunctionCallError {
+    FunctionCallError::CompilationError(CompilationError::WasmtimeCompileError { msg })
+}
+
+#[wasm_bindgen]
+impl Logic {
+    /// Execute the `method_name` of the contract `code` the same way nearcore would.
+    ///
+    /// `memory` must be the memory this logic has been constructed with. The outcome of the
+    /// execution is returned. If the execution fails, an error with the [`FunctionCallError`] in
+    /// its `function_call_error` property is thrown instead, and the outcome (which has the same
+    /// error in its `aborted` field) is available from [`Logic::outcome`].
+    pub fn execute(
+        &self,
+        code: &[u8],
+        method_name: &str,
+        memory: &js_sys::WebAssembly::Memory,
+    ) -> SResult<JsValue, JsValue> {
+        let abort = |error| function_call_error_to_js(&self.abort(error));
+        let code_len = code.len() as u64;
+        self.before_loading_executable(method_name, code_len)
+            .map_err(|e| function_call_error_to_js(&e))?;
+        let config = self.external().config().clone();
+        let prepared =
+            crate::prepare::prepare_contract(code, config.wasm_config()).map_err(|e| {
+                self.abort(e.function_call_error.clone());
+                prepare_error_to_js(&e)
+            })?;
+        let module = js_sys::WebAssembly::Module::new(&Uint8Array::from(&prepared[..]))
+            .map_err(|e| abort(compile_error(js_error_message(&e))))?;
+        let imports = self.imports(memory)?;
+        let instance =
+            js_sys::WebAssembly::Instance::new(&module, &imports).map_err(|e| self.fail(&e))?;
+        *self.instance.borrow_mut() = Some(instance.clone());
+        self.after_loading_executable(code_len)
+            .map_err(|e| function_call_error_to_js(&e))?;
+        resolve_method(code, method_name)
+            .map_err(|e| abort(FunctionCallError::MethodResolveError(e)))?;
+        let method: js_sys::Function =
+            js_sys::Reflect::get(&instance.exports(), &method_name.into())?.unchecked_into();
+        method
+            .call0(&JsValue::UNDEFINED)
+            .map_err(|e| self.fail(&e))?;
+        self.outcome().map_err(Into::into)
+    }
+
+    /// The import object to instantiate the contract with, for the embedders that instantiate
+    /// the contract themselves rather than with [`Logic::execute`].
+    ///
+    /// Contains all of the host functions enabled by the runtime config, along with the contract
+    /// `memory`, which must be the memory this logic has been constructed with.
+    pub fn imports(
+        &self,
+        memory: &js_sys::WebAssembly::Memory,
+    ) -> SResult<js_sys::Object, JsValue> {
+        let config = self.external().config().clone();
+        super::imports::js_imports(
+            &self.logic,
+            &self.tracer,
+            &self.aborted,
+            memory,
+            config.wasm_config(),
+        )
+    }
+
+    /// The contract instance, once it has been instantiated by [`Logic::execute`].
+    pub fn instance(&self) -> Option<js_sys::WebAssembly::Instance> {
+        self.instance.borrow().clone()
+    }
+}
@@ -0,0 +1,476 @@
+//! The host functions contracts may import.
+//!
+//! This is the single list all of the ways to link a contract to [`VMLogic`] are generated from.
+//! Each entry has the form
+//!
+//! ```text
+//! #[config_field] import_name as vmlogic_method<[arg: type, ...] -> [return type]>
+//! ```
+//!
+//! The optional attribute names the `near_parameters::vm::Config` flag the function is gated
+//! behind and the optional `as` clause names the [`VMLogic`] method implementing the function if it
+//! differs from the import name.
+use super::logic::errors::FunctionCallError;
+use super::logic::{VMLogic, VMLogicError};
+use super::trace::{PendingCall, RawArg, ReturnValue, Tracer};
+use finite_wasm::wasmparser::ValType;
+use near_parameters::vm;
+use serde::Serialize as _;
+use std::cell::RefCell;
+use std::fmt;
+use std::rc::Rc;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast as _;
+
+/// Invoke the `$callback` macro with the name of the import module followed by the list of host
+/// functions contracts may import from it.
+macro_rules! for_each_host_function {
+    ($callback:ident) => {
+        $callback! {
+            "env";
+            read_register<[register_id: u64, ptr: u64] -> []>,
+            register_len<[register_id: u64] -> [u64]>,
+            write_register<[register_id: u64, data_len: u64, data_ptr: u64] -> []>,
+            current_account_id<[register_id: u64] -> []>,
+            signer_account_id<[register_id: u64] -> []>,
+            signer_account_pk<[register_id: u64] -> []>,
+            predecessor_account_id<[register_id: u64] -> []>,
+            input<[register_id: u64] -> []>,
+            block_index<[] -> [u64]>,
+            block_timestamp<[] -> [u64]>,
+            epoch_height<[] -> [u64]>,
+            storage_usage<[] -> [u64]>,
+            account_balance<[balance_ptr: u64] -> []>,
+            account_locked_balance<[balance_ptr: u64] -> []>,
+            attached_deposit<[balance_ptr: u64] -> []>,
+            prepaid_gas<[] -> [u64]>,
+            used_gas<[] -> [u64]>,
+            random_seed<[register_id: u64] -> []>,
+            sha256<[value_len: u64, value_ptr: u64, register_id: u64] -> []>,
+            keccak256<[value_len: u64, value_ptr: u64, register_id: u64] -> []>,
+            keccak512<[value_len: u64, value_ptr: u64, register_id: u64] -> []>,
+            #[ed25519_verify] ed25519_verify<[
+                signature_len: u64,
+                signature_ptr: u64,
+                message_len: u64,
+                message_ptr: u64,
+                public_key_len: u64,
+                public_key_ptr: u64
+            ] -> [u64]>,
+            #[math_extension] ripemd160<[value_len: u64, value_ptr: u64, register_id: u64] -> []>,
+            #[math_extension] ecrecover<[
+                hash_len: u64,
+                hash_ptr: u64,
+                sig_len: u64,
+                sig_ptr: u64,
+                v: u64,
+                malleability_flag: u64,
+                register_id: u64
+            ] -> [u64]>,
+            value_return<[value_len: u64, value_ptr: u64] -> []>,
+            panic<[] -> []>,
+            panic_utf8<[len: u64, ptr: u64] -> []>,
+            log_utf8<[len: u64, ptr: u64] -> []>,
+            log_utf16<[len: u64, ptr: u64] -> []>,
+            abort<[msg_ptr: u32, filename_ptr: u32, line: u32, col: u32] -> []>,
+            promise_create<[
+                account_id_len: u64,
+                account_id_ptr: u64,
+                method_name_len: u64,
+                method_name_ptr: u64,
+                arguments_len: u64,
+                arguments_ptr: u64,
+                amount_ptr: u64,
+                gas: u64
+            ] -> [u64]>,
+            promise_then<[
+                promise_idx: u64,
+                account_id_len: u64,
+                account_id_ptr: u64,
+                method_name_len: u64,
+                method_name_ptr: u64,
+                arguments_len: u64,
+                arguments_ptr: u64,
+                amount_ptr: u64,
+                gas: u64
+            ] -> [u64]>,
+            promise_and<[promise_idx_ptr: u64, promise_idx_count: u64] -> [u64]>,
+            promise_batch_create<[account_id_len: u64, account_id_ptr: u64] -> [u64]>,
+            promise_batch_then<[promise_idx: u64, account_id_len: u64, account_id_ptr: u64] -> [u64]>,
+            promise_batch_action_create_account<[promise_idx: u64] -> []>,
+            promise_batch_action_deploy_contract<[
+                promise_idx: u64,
+                code_len: u64,
+                code_ptr: u64
+            ] -> []>,
+            promise_batch_action_function_call<[
+                promise_idx: u64,
+                method_name_len: u64,
+                method_name_ptr: u64,
+                arguments_len: u64,
+                arguments_ptr: u64,
+                amount_ptr: u64,
+                gas: u64
+            ] -> []>,
+            #[function_call_weight] promise_batch_action_function_call_weight<[
+                promise_idx: u64,
+                method_name_len: u64,
+                method_name_ptr: u64,
+                arguments_len: u64,
+                arguments_ptr: u64,
+                amount_ptr: u64,
+                gas: u64,
+                gas_weight: u64
+            ] -> []>,
+            promise_batch_action_transfer<[promise_idx: u64, amount_ptr: u64] -> []>,
+            promise_batch_action_stake<[
+                promise_idx: u64,
+                amount_ptr: u64,
+                public_key_len: u64,
+                public_key_ptr: u64
+            ] -> []>,
+            promise_batch_action_add_key_with_full_access<[
+                promise_idx: u64,
+                public_key_len: u64,
+                public_key_ptr: u64,
+                nonce: u64
+            ] -> []>,
+            promise_batch_action_add_key_with_function_call<[
+                promise_idx: u64,
+                public_key_len: u64,
+                public_key_ptr: u64,
+                nonce: u64,
+                allowance_ptr: u64,
+                receiver_id_len: u64,
+                receiver_id_ptr: u64,
+                method_names_len: u64,
+                method_names_ptr: u64
+            ] -> []>,
+            promise_batch_action_delete_key<[
+                promise_idx: u64,
+                public_key_len: u64,
+                public_key_ptr: u64
+            ] -> []>,
+            promise_batch_action_delete_account<[
+                promise_idx: u64,
+                beneficiary_id_len: u64,
+                beneficiary_id_ptr: u64
+            ] -> []>,
+            #[yield_resume_host_functions] promise_yield_create<[
+                method_name_len: u64,
+                method_name_ptr: u64,
+                arguments_len: u64,
+                arguments_ptr: u64,
+                gas: u64,
+                gas_weight: u64,
+                register_id: u64
+            ] -> [u64]>,
+            #[yield_resume_host_functions] promise_yield_resume<[
+                data_id_len: u64,
+                data_id_ptr: u64,
+                payload_len: u64,
+                payload_ptr: u64
+            ] -> [u32]>,
+            promise_results_count<[] -> [u64]>,
+            promise_result<[result_idx: u64, register_id: u64] -> [u64]>,
+            promise_return<[promise_idx: u64] -> []>,
+            storage_write<[
+                key_len: u64,
+                key_ptr: u64,
+                value_len: u64,
+                value_ptr: u64,
+                register_id: u64
+            ] -> [u64]>,
+            storage_read<[key_len: u64, key_ptr: u64, register_id: u64] -> [u64]>,
+            storage_remove<[key_len: u64, key_ptr: u64, register_id: u64] -> [u64]>,
+            storage_has_key<[key_len: u64, key_ptr: u64] -> [u64]>,
+            storage_iter_prefix<[prefix_len: u64, prefix_ptr: u64] -> [u64]>,
+            storage_iter_range<[start_len: u64, start_ptr: u64, end_len: u64, end_ptr: u64] -> [u64]>,
+            storage_iter_next<[iterator_id: u64, key_register_id: u64, value_register_id: u64] -> [u64]>,
+            gas as gas_seen_from_wasm<[opcodes: u32] -> []>,
+            burn_gas<[gas: u64] -> []>,
+            validator_stake<[account_id_len: u64, account_id_ptr: u64, stake_ptr: u64] -> []>,
+            validator_total_stake<[stake_ptr: u64] -> []>,
+            #[alt_bn128] alt_bn128_g1_multiexp<[value_len: u64, value_ptr: u64, register_id: u64] -> []>,
+            #[alt_bn128] alt_bn128_g1_sum<[value_len: u64, value_ptr: u64, register_id: u64] -> []>,
+            #[alt_bn128] alt_bn128_pairing_check<[value_len: u64, value_ptr: u64] -> [u64]>,
+            bls12381_p1_sum<[value_len: u64, value_ptr: u64, register_id: u64] -> [u64]>,
+            bls12381_p2_sum<[value_len: u64, value_ptr: u64, register_id: u64] -> [u64]>,
+            bls12381_g1_multiexp<[value_len: u64, value_ptr: u64, register_id: u64] -> [u64]>,
+            bls12381_g2_multiexp<[value_len: u64, value_ptr: u64, register_id: u64] -> [u64]>,
+            bls12381_map_fp_to_g1<[value_len: u64, value_ptr: u64, register_id: u64] -> [u64]>,
+            bls12381_map_fp2_to_g2<[value_len: u64, value_ptr: u64, register_id: u64] -> [u64]>,
+            bls12381_pairing_check<[value_len: u64, value_ptr: u64] -> [u64]>,
+            bls12381_p1_decompress<[value_len: u64, value_ptr: u64, register_id: u64] -> [u64]>,
+            bls12381_p2_decompress<[value_len: u64, value_ptr: u64, register_id: u64] -> [u64]>,
+        }
+    };
+}
+
+/// Invoke the `$callback` macro with the functions the finite-wasm instrumentation inserts calls
+/// to. These are imported from a module contracts cannot import from themselves.
+macro_rules! for_each_instrumentation_function {
+    ($callback:ident) => {
+        $callback! {
+            "internal";
+            finite_wasm_gas<[gas: u64] -> []>,
+            finite_wasm_stack<[operand_size: u64, frame_size: u64] -> []>,
+            finite_wasm_unstack<[operand_size: u64, frame_size: u64] -> []>,
+        }
+    };
+}
+
+/// Call the [`VMLogic`](super::logic::VMLogic) method implementing a host function.
+macro_rules! call_host_function {
+    ($logic:expr, $name:ident, [$($arg:ident),*]) => {
+        $logic.$name($($arg),*)
+    };
+    ($logic:expr, $name:ident as $method:ident, [$($arg:ident),*]) => {
+        $logic.$method($($arg),*)
+    };
+}
+
+/// Whether a host function gated behind the `$feature` flag of the `$config` is enabled.
+macro_rules! host_function_enabled {
+    ($config:expr,) => {
+        true
+    };
+    ($config:expr, $feature:ident) => {
+        $config.$feature
+    };
+}
+
+pub(crate) use {
+    call_host_function, for_each_host_function, for_each_instrumentation_function,
+    host_function_enabled,
+};
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(js_namespace = console, js_name = log)]
+    fn console_log(message: &str);
+    #[wasm_bindgen(js_namespace = console, js_name = log)]
+    fn console_log_value(value: &JsValue);
+    #[wasm_bindgen(js_namespace = console, js_name = groupCollapsed)]
+    fn console_group_collapsed(label: &str);
+    #[wasm_bindgen(js_namespace = console, js_name = groupEnd)]
+    fn console_group_end();
+}
+
+/// Conversion of the arguments host functions are called with by the browser.
+trait FromJs: Sized {
+    fn from_js(value: Option<&JsValue>) -> Result<Self, JsValue>;
+}
+
+impl FromJs for u64 {
+    fn from_js(value: Option<&JsValue>) -> Result<Self, JsValue> {
+        // wasm `i64`s are passed as signed `BigInt`s.
+        let value = value.cloned().unwrap_or(JsValue::UNDEFINED);
+        i64::try_from(value)
+            .map(|value| value as u64)
+            .map_err(|_| JsError::new("expected an i64 argument").into())
+    }
+}
+
+impl FromJs for u32 {
+    fn from_js(value: Option<&JsValue>) -> Result<Self, JsValue> {
+        value
+            .and_then(JsValue::as_f64)
+            .map(|value| value as i32 as u32)
+            .ok_or_else(|| JsError::new("expected an i32 argument").into())
+    }
+}
+
+/// Conversion of the values returned by host functions to the browser.
+trait IntoJs {
+    fn into_js(self) -> JsValue;
+}
+
+impl IntoJs for () {
+    fn into_js(self) -> JsValue {
+        JsValue::UNDEFINED
+    }
+}
+
+impl IntoJs for u64 {
+    fn into_js(self) -> JsValue {
+        JsValue::from(self)
+    }
+}
+
+impl IntoJs for u32 {
+    fn into_js(self) -> JsValue {
+        JsValue::from(self)
+    }
+}
+
+/// Wrap the `host_function` into a JS function taking any number of arguments.
+///
+/// `Closure`s can only be constructed for a limited number of arguments, fewer than some of the
+/// host functions take.
+fn variadic(
+    host_function: impl FnMut(js_sys::Array) -> Result<JsValue, JsValue> + 'static,
+) -> Result<JsValue, JsValue> {
+    let closure =
+        Closure::<dyn FnMut(js_sys::Array) -> Result<JsValue, JsValue>>::new(host_function);
+    let trampoline = js_sys::Function::new_with_args("f", "return (...args) => f(args)");
+    trampoline.call1(&JsValue::NULL, &closure.into_js_value())
+}
+
+/// Get the object holding the functions of the import `module`, creating it if necessary.
+fn import_module(imports: &js_sys::Object, module: &str) -> Result<js_sys::Object, JsValue> {
+    let existing = js_sys::Reflect::get(imports, &module.into())?;
+    if existing.is_object() {
+        return Ok(existing.unchecked_into());
+    }
+    let object = js_sys::Object::new();
+    js_sys::Reflect::set(imports, &module.into(), &object)?;
+    Ok(object)
+}
+
+/// Whether contracts may import a host function called `name`, in any of the protocol versions.
+pub(crate) fn is_host_function(name: &str) -> bool {
+    macro_rules! host_function_names {
+        ($module:literal; $(
+            $(#[$feature:ident])?
+            $name:ident $(as $method:ident)? <[ $($arg:ident : $ty:ident),* ] -> [ $($ret:ident),* ]>,
+        )*) => {
+            [$(stringify!($name)),*]
+        };
+    }
+    for_each_host_function!(host_function_names).contains(&name)
+}
+
+/// The signature of a host function, as contracts import it.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) struct HostFunctionType<'a> {
+    pub(crate) params: &'a [ValType],
+    pub(crate) results: &'a [ValType],
+}
+
+macro_rules! value_type {
+    (u64) => {
+        ValType::I64
+    };
+    (u32) => {
+        ValType::I32
+    };
+}
+
+/// The signature of the host function `name`, if contracts may import it with the `config`.
+pub(crate) fn host_function_type(
+    config: &vm::Config,
+    name: &str,
+) -> Option<HostFunctionType<'static>> {
+    macro_rules! find_host_function {
+        ($module:literal; $(
+            $(#[$feature:ident])?
+            $name:ident $(as $method:ident)? <[ $($arg:ident : $ty:ident),* ] -> [ $($ret:ident),* ]>,
+        )*) => {
+            $(
+                if name == stringify!($name) && host_function_enabled!(config, $($feature)?) {
+                    return Some(HostFunctionType {
+                        params: &[$(value_type!($ty)),*],
+                        results: &[$(value_type!($ret)),*],
+                    });
+                }
+            )*
+        };
+    }
+    for_each_host_function!(find_host_function);
+    None
+}
+
+impl fmt::Display for HostFunctionType<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let list = |types: &[ValType]| {
+            types
+                .iter()
+                .map(|ty| format!("{ty:?}").to_lowercase())
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        write!(f, "({}) -> ({})", list(self.params), list(self.results))
+    }
+}
+
+/// Whether calls to the host functions of the import `module` are traced.
+///
+/// Only the functions contracts import themselves are; the instrumentation functions are called
+/// far too often for their trace to be of use.
+pub(crate) fn is_traced(module: &str) -> bool {
+    module == "env"
+}
+
+/// Convert the `error` returned by a host function into the exception thrown to the contract,
+/// recording it as the reason the execution has been `aborted`.
+fn host_error_to_js(error: VMLogicError, aborted: &RefCell<Option<FunctionCallError>>) -> JsValue {
+    match FunctionCallError::try_from(error) {
+        Ok(error) => {
+            let error = aborted.borrow_mut().get_or_insert(error).clone();
+            super::function_call_error_to_js(&error)
+        }
+        Err(error) => JsError::from(error).into(),
+    }
+}
+
+/// Build the import object for instantiating a contract in the browser, with all of the host
+/// functions enabled by the `config` calling into the `logic`.
+///
+/// Logs are printed to the console as they are made, and the errors returned by the host functions
+/// are recorded into `aborted`.
+pub(crate) fn js_imports(
+    logic: &Rc<RefCell<VMLogic>>,
+    tracer: &Tracer,
+    aborted: &Rc<RefCell<Option<FunctionCallError>>>,
+    memory: &js_sys::WebAssembly::Memory,
+    config: &vm::Config,
+) -> Result<js_sys::Object, JsValue> {
+    let imports = js_sys::Object::new();
+    let env = import_module(&imports, "env")?;
+    js_sys::Reflect::set(&env, &"memory".into(), memory)?;
+    macro_rules! define_host_functions {
+        ($module:literal; $(
+            $(#[$feature:ident])?
+            $name:ident $(as $method:ident)? <[ $($arg:ident : $ty:ident),* ] -> [ $($ret:ident),* ]>,
+        )*) => {
+            let module = import_module(&imports, $module)?;
+            $(
+                if host_function_enabled!(config, $($feature)?) {
+                    let logic = Rc::clone(logic);
+                    let tracer = is_traced($module).then(|| tracer.clone());
+                    let aborted = Rc::clone(aborted);
+                    let function = variadic(move |args| {
+                        let mut logic = logic.borrow_mut();
+                        let mut args = args.iter();
+                        $(let $arg = <$ty as FromJs>::from_js(args.next().as_ref())?;)*
+                        let logs_before = logic.logs().len();
+                        let pending = PendingCall::begin(
+                            tracer.as_ref(),
+                            &mut logic,
+                            stringify!($name),
+                            &[$((stringify!($arg), RawArg::raw(&$arg))),*],
+                        );
+                        let result = call_host_function!(logic, $name $(as $method)?, [$($arg),*]);
+                        if let Some(pending) = pending {
+                            let call = pending.end(&mut logic, result.as_ref().map(ReturnValue::traced));
+                            console_group_collapsed(&call.to_string());
+                            if let Ok(value) = call.serialize(&super::js_serializer()) {
+                                console_log_value(&value);
+                            }
+                            console_group_end();
+                        }
+                        for log in &logic.logs()[logs_before..] {
+                            console_log(log);
+                        }
+                        result.map(IntoJs::into_js).map_err(|e| host_error_to_js(e, &aborted))
+                    })?;
+                    js_sys::Reflect::set(&module, &stringify!($name).into(), &function)?;
+                }
+            )*
+        };
+    }
+    for_each_host_function!(define_host_functions);
+    for_each_instrumentation_function!(define_host_functions);
+    Ok(imports)
+}
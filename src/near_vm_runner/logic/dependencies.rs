@@ -488,4 +488,8 @@ pub trait External {
     ///
     /// Panics if `ReceiptIndex` is invalid.
     fn get_receipt_receiver(&self, receipt_index: ReceiptIndex) -> &AccountId;
+
+    /// Allows the embedder to recover its concrete `External` implementation once the execution
+    /// is over, e.g. to inspect the receipts that were created.
+    fn as_any(&self) -> &dyn std::any::Any;
 }
@@ -21,18 +21,34 @@ pub enum VMRunnerError {
 /// See the doc comment on `VMResult` for an explanation what the difference
 /// between this and a `VMRunnerError` is. And see `PartialExecutionStatus`
 /// for what gets stored on chain.
-#[derive(Debug, PartialEq, Eq, serde::Serialize)]
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
 pub enum FunctionCallError {
     /// Wasm compilation error
     CompilationError(CompilationError),
+    /// Wasm binary env link error
+    ///
+    /// Note: this is only to be used for deserialization purposes.
+    /// Any new variant should use `CompilationError::WasmerCompileError` or similar.
+    LinkError {
+        msg: String,
+    },
     /// Import/export resolve error
     MethodResolveError(MethodResolveError),
+    /// A trap happened during execution of a binary
+    WasmTrap(WasmTrap),
+    /// The execution failed in a way the engine does not tell apart, e.g. a trap in the browser,
+    /// whose kind is only described by the message.
+    WasmUnknownError {
+        debug_message: String,
+    },
     HostError(HostError),
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
 pub enum MethodResolveError {
     MethodEmptyName,
+    MethodNotFound,
+    MethodInvalidSignature,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
@@ -85,6 +101,28 @@ pub enum PrepareError {
     TooManyLocals,
 }
 
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub enum WasmTrap {
+    /// An `unreachable` opcode was executed.
+    Unreachable,
+    /// Call indirect incorrect signature trap.
+    IncorrectCallIndirectSignature,
+    /// Memory out of bounds trap.
+    MemoryOutOfBounds,
+    /// Call indirect out of bounds trap.
+    CallIndirectOOB,
+    /// An arithmetic exception, e.g. divided by zero.
+    IllegalArithmetic,
+    /// Misaligned atomic access trap.
+    MisalignedAtomicAccess,
+    /// Indirect call to null.
+    IndirectCallToNull,
+    /// Stack overflow.
+    StackOverflow,
+    /// Generic trap.
+    GenericTrap,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
 pub enum HostError {
     /// String encoding is bad UTF-16 sequence
@@ -215,7 +253,7 @@ pub enum HostError {
     },
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum VMLogicError {
     /// Errors coming from native Wasm VM.
     HostError(HostError),
@@ -298,7 +336,16 @@ impl fmt::Display for FunctionCallError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
         match self {
             FunctionCallError::CompilationError(e) => e.fmt(f),
+            FunctionCallError::LinkError { msg } => write!(f, "{:?}", msg),
             FunctionCallError::MethodResolveError(e) => e.fmt(f),
+            FunctionCallError::WasmTrap(e) => write!(f, "WebAssembly trap: {}", e),
+            FunctionCallError::WasmUnknownError { debug_message } => {
+                write!(
+                    f,
+                    "Unknown error during Wasm contract execution: {}",
+                    debug_message
+                )
+            }
             FunctionCallError::HostError(e) => e.fmt(f),
         }
     }
@@ -321,6 +368,26 @@ impl fmt::Display for CompilationError {
     }
 }
 
+impl fmt::Display for WasmTrap {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        match self {
+            WasmTrap::Unreachable => write!(f, "An `unreachable` opcode was executed."),
+            WasmTrap::IncorrectCallIndirectSignature => {
+                write!(f, "Call indirect incorrect signature trap.")
+            }
+            WasmTrap::MemoryOutOfBounds => write!(f, "Memory out of bounds trap."),
+            WasmTrap::CallIndirectOOB => write!(f, "Call indirect out of bounds trap."),
+            WasmTrap::IllegalArithmetic => {
+                write!(f, "An arithmetic exception, e.g. divided by zero.")
+            }
+            WasmTrap::MisalignedAtomicAccess => write!(f, "Misaligned atomic access trap."),
+            WasmTrap::IndirectCallToNull => write!(f, "Indirect call to null."),
+            WasmTrap::StackOverflow => write!(f, "Stack overflow."),
+            WasmTrap::GenericTrap => write!(f, "Generic trap."),
+        }
+    }
+}
+
 impl fmt::Display for MethodResolveError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
         fmt::Debug::fmt(self, f)
@@ -267,6 +267,10 @@ impl VMLogic {
         &self.result_state.logs
     }
 
+    pub(crate) fn ext(&self) -> &dyn External {
+        &*self.ext
+    }
+
     pub(crate) fn config(&self) -> &Config {
         &self.config
     }
@@ -279,6 +283,20 @@ impl VMLogic {
         &mut self.memory
     }
 
+    /// Read the guest memory without paying for it, for debugging purposes.
+    pub(crate) fn peek_memory(&self, ptr: u64, len: u64) -> Option<Vec<u8>> {
+        let slice = MemSlice { ptr, len };
+        self.memory
+            .view_for_free(slice)
+            .ok()
+            .map(|data| data.into_owned())
+    }
+
+    /// Contents of the register without paying for reading it, for debugging purposes.
+    pub(crate) fn peek_register(&self, register_id: u64) -> Option<&[u8]> {
+        self.registers.get_for_free(register_id)
+    }
+
     // #########################
     // # Finite-wasm internals #
     // #########################
@@ -14,4 +14,4 @@ pub use context::VMContext;
 pub use dependencies::{External, MemSlice, MemoryLike, TrieNodesCount, ValuePtr};
 pub use errors::{HostError, VMLogicError};
 pub use gas_counter::{with_ext_cost_counter, GasCounter};
-pub use logic::{ExecutionResultState, VMLogic};
+pub use logic::{ExecutionResultState, VMLogic, VMOutcome};
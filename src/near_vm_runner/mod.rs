@@ -1,33 +1,109 @@
+mod access_key;
+mod config;
 pub mod errors;
+mod execute;
+mod imports;
 pub mod logic;
+#[cfg(feature = "native")]
+pub mod native;
 pub mod profile;
-
+mod receipts;
+mod scheduler;
+mod staking;
+mod storage_diff;
+mod trace;
+mod trie;
+mod world;
+
+pub(crate) use access_key::{verify_access_key, MIN_GAS_PRICE};
+pub use config::RuntimeConfig;
+pub(crate) use imports::{host_function_type, is_host_function, HostFunctionType};
 use js_sys::{ArrayBuffer, Uint8Array};
-use logic::types::PromiseIndex;
-use logic::{ExecutionResultState, External, VMContext, VMLogicError, ValuePtr};
+use logic::errors::FunctionCallError;
+use logic::types::PromiseResult;
+use logic::{ExecutionResultState, External, HostError, VMContext, VMLogicError, ValuePtr};
+use near_crypto::{KeyType, PublicKey};
+use near_primitives_core::config::ViewConfig;
 use near_primitives_core::hash::CryptoHash;
 use near_primitives_core::types::{
-    AccountId, Balance, BlockHeight, EpochHeight, Gas, ProtocolVersion, StorageUsage,
+    AccountId, Balance, BlockHeight, Gas, ProtocolVersion, StorageUsage,
 };
 use near_primitives_core::version::ProtocolFeature;
 pub use profile::ProfileDataV3;
+use receipts::{Action, DataReceipt, DataReceiver, Receipt};
+pub use scheduler::{ReceiptScheduler, ScheduledCall};
 use serde::Serialize as _;
 use serde_with::serde_as;
-use std::collections::BTreeMap;
+use std::cell::{Ref, RefCell};
+use std::collections::{BTreeMap, BTreeSet};
+use std::rc::Rc;
 use std::result::Result as SResult;
 use std::str::FromStr as _;
 use std::sync::{Arc, Mutex, MutexGuard};
 use wasm_bindgen::prelude::*;
+pub use world::{AccountSnapshot, World};
 
-fn js_serializer() -> serde_wasm_bindgen::Serializer {
+pub(crate) fn js_serializer() -> serde_wasm_bindgen::Serializer {
     serde_wasm_bindgen::Serializer::new()
         .serialize_missing_as_null(true)
         .serialize_large_number_types_as_bigints(true)
         .serialize_bytes_as_arrays(false)
 }
 
+/// A JavaScript `Error` for the `error`, with the error itself available as structured data in its
+/// `function_call_error` property, e.g. `{HostError: {KeyLengthExceeded: {length, limit}}}`.
+fn function_call_error_to_js(error: &FunctionCallError) -> JsValue {
+    let js_error = js_sys::Error::new(&error.to_string());
+    if let Ok(value) = error.serialize(&js_serializer()) {
+        let _ = js_sys::Reflect::set(&js_error, &"function_call_error".into(), &value);
+    }
+    js_error.into()
+}
+
+/// Like [`function_call_error_to_js`], for a contract that could not be prepared. The message
+/// describes what exactly is wrong with the contract, and the `offset` and `function_index`
+/// properties point at where the problem is, if known. The `imports` property lists the imports
+/// the runtime would fail to link, as `{name, message, offset}` objects.
+pub(crate) fn prepare_error_to_js(error: &crate::prepare::Error) -> JsValue {
+    let js_error = function_call_error_to_js(&error.function_call_error);
+    let imports = error
+        .imports
+        .serialize(&js_serializer())
+        .unwrap_or(JsValue::UNDEFINED);
+    let properties = [
+        ("message", JsValue::from(error.to_string())),
+        ("offset", error.offset.map(|offset| offset as f64).into()),
+        ("function_index", error.function_index.into()),
+        ("imports", imports),
+    ];
+    for (name, value) in properties {
+        let _ = js_sys::Reflect::set(&js_error, &name.into(), &value);
+    }
+    js_error
+}
+
+/// The `FunctionCallError` for an `error` thrown by the browser while instantiating or calling a
+/// contract, which was not thrown by a host function.
+///
+/// The kinds of the traps are not exposed by the browsers other than in their messages, which
+/// differ between the engines, so traps are reported as `WasmUnknownError`s with the message.
+fn function_call_error_from_js(error: &JsValue) -> FunctionCallError {
+    let msg = js_error_message(error);
+    if error.is_instance_of::<js_sys::WebAssembly::LinkError>() {
+        return FunctionCallError::LinkError { msg };
+    }
+    FunctionCallError::WasmUnknownError { debug_message: msg }
+}
+
+fn js_error_message(error: &JsValue) -> String {
+    match error.dyn_ref::<js_sys::Error>() {
+        Some(error) => error.message().into(),
+        None => format!("{error:?}"),
+    }
+}
+
 #[serde_as]
-#[derive(serde::Serialize, serde::Deserialize, Default)]
+#[derive(serde::Serialize, serde::Deserialize, Default, Clone)]
 #[serde(transparent)]
 struct StoreMap {
     #[serde_as(as = "BTreeMap<serde_with::base64::Base64, serde_with::base64::Base64>")]
@@ -35,9 +111,35 @@ struct StoreMap {
 }
 
 #[wasm_bindgen]
-#[derive(Clone)]
+#[derive(Clone, Default)]
 pub struct Store(Arc<Mutex<StoreMap>>);
 
+impl Store {
+    /// A copy of the store that does not share the contents with this handle.
+    pub(crate) fn deep_clone(&self) -> Self {
+        Self(Arc::new(Mutex::new(self.guard().clone())))
+    }
+
+    /// Replace the contents of this store with those of `other`.
+    pub(crate) fn restore(&self, other: &Store) {
+        let contents = other.guard().clone();
+        *self.guard() = contents;
+    }
+}
+
+impl serde::Serialize for Store {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> SResult<S::Ok, S::Error> {
+        self.guard().serialize(serializer)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Store {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> SResult<Self, D::Error> {
+        let map = <StoreMap as serde::Deserialize>::deserialize(deserializer)?;
+        Ok(Self(Arc::new(Mutex::new(map))))
+    }
+}
+
 #[wasm_bindgen]
 impl Store {
     #[wasm_bindgen(constructor)]
@@ -95,61 +197,330 @@ impl Store {
     }
 }
 
-struct Receipt {
-    receiver: AccountId,
-    is_promise_yield: bool,
+impl Store {
+    /// The keys along with the lengths of their values.
+    pub(crate) fn value_lengths(&self) -> Vec<(Vec<u8>, usize)> {
+        self.guard()
+            .map
+            .iter()
+            .map(|(key, value)| (key.clone(), value.len()))
+            .collect()
+    }
+
+    /// Storage usage of the entries, including the `num_extra_bytes_record` nearcore charges for
+    /// each of them on top of the key and value.
+    pub(crate) fn storage_usage(&self, num_extra_bytes_record: StorageUsage) -> StorageUsage {
+        self.guard()
+            .map
+            .iter()
+            .map(|(key, value)| key.len() as u64 + value.len() as u64 + num_extra_bytes_record)
+            .sum()
+    }
+
+    pub(crate) fn keys_with_prefix(&self, prefix: &[u8]) -> Vec<Vec<u8>> {
+        self.guard()
+            .map
+            .range(prefix.to_vec()..)
+            .map(|(key, _)| key)
+            .take_while(|key| key.starts_with(prefix))
+            .cloned()
+            .collect()
+    }
 }
 
-struct DataReceipt {
-    #[allow(dead_code)]
-    data_id: CryptoHash,
-    #[allow(dead_code)]
-    data: Vec<u8>,
+/// Hashes of the simulated chain, from which the ids of the receipts and data created by an
+/// execution are derived.
+#[derive(Clone, Copy, Default)]
+pub(crate) struct ChainHashes {
+    /// Hash of the action being executed.
+    pub(crate) action_hash: CryptoHash,
+    pub(crate) prev_block_hash: CryptoHash,
+    pub(crate) block_hash: CryptoHash,
+    /// The random value of the block. When set, the random seed of the execution is derived from
+    /// it and the action hash like nearcore does, instead of being taken from the context.
+    pub(crate) random_value: Option<CryptoHash>,
 }
 
 #[wasm_bindgen]
 pub struct DebugExternal {
+    account_id: AccountId,
     store: Store,
-    protocol_version: ProtocolVersion,
-    action_hash: CryptoHash,
-    prev_block_hash: CryptoHash,
-    last_block_hash: CryptoHash,
+    config: RuntimeConfig,
+    chain: ChainHashes,
     block_height: BlockHeight,
     data_count: u64,
     validators: BTreeMap<AccountId, Balance>,
     receipts: Vec<Receipt>,
     promise_yield_receipt_index: BTreeMap<CryptoHash, usize>,
+    /// Receipts yielded by the account in the earlier executions, which this execution may
+    /// resume.
+    yielded: BTreeSet<CryptoHash>,
     data_receipts: Vec<DataReceipt>,
+    storage_changes: storage_diff::StorageChanges,
+    /// Storage usage of the account other than the contract storage, which does not change
+    /// during the execution.
+    storage_usage_without_data: StorageUsage,
+    /// The balance and the locked balance of the account in the world when the execution starts.
+    balances: (Balance, Balance),
+    /// See [`DebugExternal::with_trie_model`].
+    trie: Option<trie::TrieModel>,
 }
 
 #[wasm_bindgen]
 impl DebugExternal {
+    /// Construct the external for executing a call to the `context`'s current account, which
+    /// must exist in the `world`.
     #[wasm_bindgen(constructor)]
-    pub fn new(store: &Store, context: &Context, protocol_version: ProtocolVersion) -> Self {
-        Self {
-            store: store.clone(),
-            action_hash: CryptoHash::default(),
+    pub fn new(
+        world: &World,
+        context: &Context,
+        protocol_version: ProtocolVersion,
+    ) -> Result<Self> {
+        let config = RuntimeConfig::new(protocol_version);
+        Self::with_chain_hashes(world, context, config, ChainHashes::default())
+            .map_err(|e| JsError::new(&e))
+    }
+
+    /// Hash of the action being executed, from which the ids of the receipts and data created
+    /// by the execution are derived. Zeroes by default.
+    pub fn with_action_hash(mut self, action_hash: &str) -> Result<Self> {
+        self.chain.action_hash = parse_hash(action_hash)?;
+        Ok(self)
+    }
+
+    /// Derive the action hash from the id of the receipt being executed and the index of the
+    /// function call action within it, like nearcore does. The block hashes must have been set
+    /// already.
+    pub fn with_receipt_id(mut self, receipt_id: &str, action_index: usize) -> Result<Self> {
+        self.chain.action_hash = create_action_hash(
+            self.config.protocol_version(),
+            &parse_hash(receipt_id)?,
+            &self.chain,
+            self.block_height,
+            action_index,
+        );
+        Ok(self)
+    }
+
+    /// Hashes of the previous and the current block, which the ids of the receipts and data are
+    /// derived from in the protocol versions before the block height is used instead. Zeroes by
+    /// default.
+    pub fn with_block_hashes(mut self, prev_block_hash: &str, block_hash: &str) -> Result<Self> {
+        self.chain.prev_block_hash = parse_hash(prev_block_hash)?;
+        self.chain.block_hash = parse_hash(block_hash)?;
+        Ok(self)
+    }
+
+    /// The random value of the block. The random seed of the execution (and of the receipts it
+    /// creates) is then derived from the random value and the action hash like nearcore does,
+    /// instead of being taken from the context.
+    pub fn with_random_value(mut self, random_value: &str) -> Result<Self> {
+        self.chain.random_value = Some(parse_hash(random_value)?);
+        Ok(self)
+    }
+
+    /// Use the `config` (and its protocol version) instead of the one the external has been
+    /// constructed with.
+    pub fn with_runtime_config(mut self, config: &RuntimeConfig) -> Self {
+        self.config = config.clone();
+        self
+    }
+
+    /// Model the trie the contract storage is kept in, so that the storage accesses are charged
+    /// the `touching_trie_node` and `read_cached_trie_node` costs and count towards the storage
+    /// proof size limit the way they would on chain.
+    ///
+    /// Without the model trie nodes are never charged for and the storage proof remains empty.
+    pub fn with_trie_model(mut self) -> Self {
+        self.trie = Some(trie::TrieModel::new(&self.account_id, &self.store));
+        self
+    }
+}
+
+impl DebugExternal {
+    pub(crate) fn with_chain_hashes(
+        world: &World,
+        context: &Context,
+        config: RuntimeConfig,
+        chain: ChainHashes,
+    ) -> SResult<Self, String> {
+        let account_id = &context.0.current_account_id;
+        let storage_usage_config = &config.fees().storage_usage_config;
+        let (store, storage_usage_without_data, balances) = world
+            .with_account(account_id, |account| {
+                let storage_usage = account.storage_usage_without_data(storage_usage_config);
+                let balances = (account.amount, account.locked);
+                (account.storage.clone(), storage_usage, balances)
+            })
+            .ok_or_else(|| format!("account {account_id} does not exist"))?;
+        Ok(Self {
+            account_id: account_id.clone(),
+            store,
+            chain,
             block_height: context.0.block_height,
-            prev_block_hash: CryptoHash::default(),
-            last_block_hash: CryptoHash::default(),
             data_count: 0,
-            validators: Default::default(),
-            protocol_version,
+            validators: world.validators_at(context.0.epoch_height),
+            config,
             receipts: Vec::new(),
             data_receipts: Vec::new(),
             promise_yield_receipt_index: Default::default(),
-        }
+            yielded: world.yielded_data_ids(account_id),
+            storage_changes: Default::default(),
+            storage_usage_without_data,
+            balances,
+            trie: None,
+        })
+    }
+
+    pub(crate) fn config(&self) -> &RuntimeConfig {
+        &self.config
+    }
+
+    pub(crate) fn chain_hashes(&self) -> &ChainHashes {
+        &self.chain
+    }
+
+    /// The random seed derived from the random value of the block, if it has been given.
+    pub(crate) fn random_seed(&self) -> Option<Vec<u8>> {
+        let random_value = self.chain.random_value?;
+        Some(create_random_seed(
+            self.config.protocol_version(),
+            &self.chain.action_hash,
+            &random_value,
+        ))
     }
 
-    fn append_action(&mut self, receipt_index: logic::types::ReceiptIndex) {
+    /// Derive a hash the same way nearcore derives receipt and data ids.
+    pub(crate) fn create_hash(&self, base: &CryptoHash, salt: u64) -> CryptoHash {
+        create_hash_upgradable(
+            self.config.protocol_version(),
+            base,
+            &self.chain.prev_block_hash,
+            &self.chain.block_hash,
+            self.block_height,
+            salt,
+        )
+    }
+
+    pub(crate) fn receipts(&self) -> &[Receipt] {
+        &self.receipts
+    }
+
+    pub(crate) fn data_receipts(&self) -> &[DataReceipt] {
+        &self.data_receipts
+    }
+
+    /// The storage usage of the account computed from its current contents, like nearcore
+    /// would, which the host functions keep track of as they change the storage.
+    pub(crate) fn storage_usage(&self) -> StorageUsage {
+        let num_extra_bytes_record = self
+            .config
+            .fees()
+            .storage_usage_config
+            .num_extra_bytes_record;
+        self.storage_usage_without_data + self.store.storage_usage(num_extra_bytes_record)
+    }
+
+    pub(crate) fn storage_diff(&self) -> storage_diff::StorageDiff {
+        self.storage_changes.diff(&self.store)
+    }
+
+    fn append_action(&mut self, receipt_index: logic::types::ReceiptIndex, action: Action) {
         self.receipts
-            .get(receipt_index as usize)
-            .expect("receipt index should have been returned from runtime");
+            .get_mut(receipt_index as usize)
+            .expect("receipt index should have been returned from runtime")
+            .actions
+            .push(action);
     }
 }
 
+/// FIXME: get this outta near_primitives?
+fn create_hash_upgradable(
+    protocol_version: ProtocolVersion,
+    base: &CryptoHash,
+    extra_hash_old: &CryptoHash,
+    extra_hash: &CryptoHash,
+    block_height: BlockHeight,
+    salt: u64,
+) -> CryptoHash {
+    const BYTES_LEN: usize = size_of::<CryptoHash>() + size_of::<CryptoHash>() + size_of::<u64>();
+    let mut bytes: Vec<u8> = Vec::with_capacity(BYTES_LEN);
+    bytes.extend_from_slice(base.as_ref());
+    if ProtocolFeature::BlockHeightForReceiptId.enabled(protocol_version) {
+        bytes.extend_from_slice(block_height.to_le_bytes().as_ref())
+    } else if protocol_version >= 42 {
+        bytes.extend_from_slice(extra_hash.as_ref())
+    } else {
+        bytes.extend_from_slice(extra_hash_old.as_ref())
+    };
+    bytes.extend(salt.to_le_bytes());
+    CryptoHash::hash_bytes(&bytes)
+}
+
+/// Mirrors `create_action_hash_from_receipt_id` in nearcore.
+pub(crate) fn create_action_hash(
+    protocol_version: ProtocolVersion,
+    receipt_id: &CryptoHash,
+    chain: &ChainHashes,
+    block_height: BlockHeight,
+    action_index: usize,
+) -> CryptoHash {
+    let salt = u64::MAX.wrapping_sub(action_index as u64);
+    create_hash_upgradable(
+        protocol_version,
+        receipt_id,
+        &chain.prev_block_hash,
+        &chain.block_hash,
+        block_height,
+        salt,
+    )
+}
+
+/// Mirrors `create_receipt_id_from_receipt_id` in nearcore.
+pub(crate) fn create_receipt_id_from_receipt_id(
+    protocol_version: ProtocolVersion,
+    receipt_id: &CryptoHash,
+    chain: &ChainHashes,
+    block_height: BlockHeight,
+    receipt_index: usize,
+) -> CryptoHash {
+    create_hash_upgradable(
+        protocol_version,
+        receipt_id,
+        &chain.prev_block_hash,
+        &chain.block_hash,
+        block_height,
+        receipt_index as u64,
+    )
+}
+
+/// Mirrors `create_random_seed` in nearcore.
+fn create_random_seed(
+    protocol_version: ProtocolVersion,
+    action_hash: &CryptoHash,
+    random_value: &CryptoHash,
+) -> Vec<u8> {
+    // `CORRECT_RANDOM_VALUE_PROTOCOL_VERSION` and `CREATE_HASH_PROTOCOL_VERSION` in nearcore.
+    let seed = if protocol_version < 33 {
+        *action_hash
+    } else if protocol_version < 38 {
+        *random_value
+    } else {
+        CryptoHash::hash_bytes(&[action_hash.as_bytes(), random_value.as_bytes()].concat())
+    };
+    seed.as_bytes().to_vec()
+}
+
+fn parse_hash(hash: &str) -> Result<CryptoHash> {
+    CryptoHash::from_str(hash).map_err(|e| JsError::new(&e.to_string()))
+}
+
 impl External for DebugExternal {
     fn storage_set(&mut self, key: &[u8], value: &[u8]) -> SResult<(), VMLogicError> {
+        self.storage_changes.touch(&self.store, key);
+        if let Some(trie) = &mut self.trie {
+            trie.change(key);
+        }
         self.store.set(key, value);
         Ok(())
     }
@@ -157,43 +528,53 @@ impl External for DebugExternal {
     fn storage_get<'a>(
         &'a self,
         key: &[u8],
-        _: near_parameters::vm::StorageGetMode,
+        mode: near_parameters::vm::StorageGetMode,
     ) -> SResult<Option<Box<dyn logic::ValuePtr + 'a>>, VMLogicError> {
-        pub struct MockedValuePtr {
+        pub struct MockedValuePtr<'a> {
             value: Vec<u8>,
+            /// The value in the trie model, if it is read from the trie.
+            trie_value: Option<trie::TrieValue<'a>>,
         }
 
-        impl MockedValuePtr {
-            pub fn new<T>(value: T) -> Self
-            where
-                T: AsRef<[u8]>,
-            {
-                MockedValuePtr {
-                    value: value.as_ref().to_vec(),
-                }
-            }
-        }
-
-        impl ValuePtr for MockedValuePtr {
+        impl ValuePtr for MockedValuePtr<'_> {
             fn len(&self) -> u32 {
                 self.value.len() as u32
             }
 
             fn deref(&self) -> SResult<Vec<u8>, VMLogicError> {
+                if let Some(trie_value) = &self.trie_value {
+                    trie_value.read();
+                }
                 Ok(self.value.clone())
             }
         }
 
+        let trie_value = self.trie.as_ref().and_then(|trie| {
+            trie.lookup(
+                key,
+                matches!(mode, near_parameters::vm::StorageGetMode::Trie),
+            )
+        });
         let v = self.store.get(key);
-        Ok(v.map(|v| Box::new(MockedValuePtr::new(&v)) as Box<_>))
+        Ok(v.map(|value| Box::new(MockedValuePtr { value, trie_value }) as Box<_>))
     }
 
     fn storage_remove(&mut self, key: &[u8]) -> SResult<(), VMLogicError> {
+        self.storage_changes.touch(&self.store, key);
+        if let Some(trie) = &mut self.trie {
+            trie.remove(key);
+        }
         self.store.remove(key);
         Ok(())
     }
 
     fn storage_remove_subtree(&mut self, prefix: &[u8]) -> SResult<(), VMLogicError> {
+        for key in self.store.keys_with_prefix(prefix) {
+            self.storage_changes.touch(&self.store, &key);
+            if let Some(trie) = &mut self.trie {
+                trie.remove(&key);
+            }
+        }
         self.store.remove_subtree(prefix);
         Ok(())
     }
@@ -201,56 +582,37 @@ impl External for DebugExternal {
     fn storage_has_key(
         &mut self,
         key: &[u8],
-        _: near_parameters::vm::StorageGetMode,
+        mode: near_parameters::vm::StorageGetMode,
     ) -> SResult<bool, VMLogicError> {
+        if let Some(trie) = &self.trie {
+            trie.lookup(
+                key,
+                matches!(mode, near_parameters::vm::StorageGetMode::Trie),
+            );
+        }
         Ok(self.store.has_key(key))
     }
 
     fn generate_data_id(&mut self) -> CryptoHash {
-        /// FIXME: get this outta near_primitives?
-        fn create_hash_upgradable(
-            protocol_version: near_primitives_core::types::ProtocolVersion,
-            base: &CryptoHash,
-            extra_hash_old: &CryptoHash,
-            extra_hash: &CryptoHash,
-            block_height: near_primitives_core::types::BlockHeight,
-            salt: u64,
-        ) -> CryptoHash {
-            const BYTES_LEN: usize =
-                size_of::<CryptoHash>() + size_of::<CryptoHash>() + size_of::<u64>();
-            let mut bytes: Vec<u8> = Vec::with_capacity(BYTES_LEN);
-            bytes.extend_from_slice(base.as_ref());
-            if ProtocolFeature::BlockHeightForReceiptId.enabled(protocol_version) {
-                bytes.extend_from_slice(block_height.to_le_bytes().as_ref())
-            } else if protocol_version >= 42 {
-                bytes.extend_from_slice(extra_hash.as_ref())
-            } else {
-                bytes.extend_from_slice(extra_hash_old.as_ref())
-            };
-            bytes.extend(salt.to_le_bytes());
-            CryptoHash::hash_bytes(&bytes)
-        }
-        let hash = create_hash_upgradable(
-            self.protocol_version,
-            &self.action_hash,
-            &self.prev_block_hash,
-            &self.last_block_hash,
-            self.block_height,
-            self.data_count,
-        );
+        let hash = self.create_hash(&self.chain.action_hash, self.data_count);
         self.data_count += 1;
         hash
     }
 
     fn get_trie_nodes_count(&self) -> logic::TrieNodesCount {
-        logic::TrieNodesCount {
-            db_reads: 0,
-            mem_reads: 0,
+        match &self.trie {
+            Some(trie) => trie.nodes_count(),
+            None => logic::TrieNodesCount {
+                db_reads: 0,
+                mem_reads: 0,
+            },
         }
     }
 
     fn get_recorded_storage_size(&self) -> usize {
-        0
+        self.trie
+            .as_ref()
+            .map_or(0, trie::TrieModel::recorded_storage_size)
     }
 
     fn validator_stake(&self, account_id: &AccountId) -> SResult<Option<Balance>, VMLogicError> {
@@ -263,13 +625,27 @@ impl External for DebugExternal {
 
     fn create_action_receipt(
         &mut self,
-        _receipt_indices: Vec<logic::types::ReceiptIndex>,
+        receipt_indices: Vec<logic::types::ReceiptIndex>,
         receiver_id: AccountId,
     ) -> SResult<logic::types::ReceiptIndex, logic::VMLogicError> {
+        let mut input_data_ids = vec![];
+        for &receipt_index in &receipt_indices {
+            let data_id = self.generate_data_id();
+            self.receipts
+                .get_mut(receipt_index as usize)
+                .ok_or(logic::HostError::InvalidReceiptIndex { receipt_index })?
+                .output_data_receivers
+                .push(DataReceiver {
+                    data_id,
+                    receiver_id: receiver_id.clone(),
+                });
+            input_data_ids.push(data_id);
+        }
         let index = self.receipts.len();
         self.receipts.push(Receipt {
-            receiver: receiver_id,
-            is_promise_yield: false,
+            receipt_indices,
+            input_data_ids,
+            ..Receipt::new(receiver_id)
         });
         Ok(index as u64)
     }
@@ -281,8 +657,9 @@ impl External for DebugExternal {
         let index = self.receipts.len();
         let data_id = self.generate_data_id();
         self.receipts.push(Receipt {
-            receiver: receiver_id,
+            input_data_ids: vec![data_id],
             is_promise_yield: true,
+            ..Receipt::new(receiver_id)
         });
         self.promise_yield_receipt_index.insert(data_id, index);
         Ok((index as u64, data_id))
@@ -297,7 +674,16 @@ impl External for DebugExternal {
             let receipt = &mut self.receipts[idx];
             assert!(receipt.is_promise_yield, "receipt should be promise yield");
             receipt.is_promise_yield = false;
-            self.data_receipts.push(DataReceipt { data_id, data });
+            self.data_receipts.push(DataReceipt {
+                data_id,
+                data: Some(data),
+            });
+            Ok(true)
+        } else if self.yielded.remove(&data_id) {
+            self.data_receipts.push(DataReceipt {
+                data_id,
+                data: Some(data),
+            });
             Ok(true)
         } else {
             Ok(false)
@@ -308,86 +694,114 @@ impl External for DebugExternal {
         &mut self,
         receipt_index: logic::types::ReceiptIndex,
     ) -> SResult<(), logic::VMLogicError> {
-        self.append_action(receipt_index);
+        self.append_action(receipt_index, Action::CreateAccount);
         Ok(())
     }
 
     fn append_action_deploy_contract(
         &mut self,
         receipt_index: logic::types::ReceiptIndex,
-        _code: Vec<u8>,
+        code: Vec<u8>,
     ) -> SResult<(), logic::VMLogicError> {
-        self.append_action(receipt_index);
+        let code_hash = CryptoHash::hash_bytes(&code);
+        self.append_action(receipt_index, Action::DeployContract { code_hash, code });
         Ok(())
     }
 
     fn append_action_function_call_weight(
         &mut self,
         receipt_index: logic::types::ReceiptIndex,
-        _method_name: Vec<u8>,
-        _args: Vec<u8>,
-        _attached_deposit: Balance,
-        _prepaid_gas: Gas,
-        _gas_weight: near_primitives_core::types::GasWeight,
+        method_name: Vec<u8>,
+        args: Vec<u8>,
+        attached_deposit: Balance,
+        prepaid_gas: Gas,
+        gas_weight: near_primitives_core::types::GasWeight,
     ) -> SResult<(), logic::VMLogicError> {
-        self.append_action(receipt_index);
+        let method_name =
+            String::from_utf8(method_name).map_err(|_| logic::HostError::InvalidMethodName)?;
+        self.append_action(
+            receipt_index,
+            Action::FunctionCall {
+                method_name,
+                args,
+                deposit: attached_deposit,
+                gas: prepaid_gas,
+                gas_weight: gas_weight.0,
+            },
+        );
         Ok(())
     }
 
     fn append_action_transfer(
         &mut self,
         receipt_index: logic::types::ReceiptIndex,
-        _deposit: Balance,
+        deposit: Balance,
     ) -> SResult<(), logic::VMLogicError> {
-        self.append_action(receipt_index);
+        self.append_action(receipt_index, Action::Transfer { deposit });
         Ok(())
     }
 
     fn append_action_stake(
         &mut self,
         receipt_index: logic::types::ReceiptIndex,
-        _stake: Balance,
-        _public_key: near_crypto::PublicKey,
+        stake: Balance,
+        public_key: near_crypto::PublicKey,
     ) {
-        self.append_action(receipt_index);
+        self.append_action(receipt_index, Action::Stake { stake, public_key });
     }
 
     fn append_action_add_key_with_full_access(
         &mut self,
         receipt_index: logic::types::ReceiptIndex,
-        _public_key: near_crypto::PublicKey,
-        _nonce: near_primitives_core::types::Nonce,
+        public_key: near_crypto::PublicKey,
+        nonce: near_primitives_core::types::Nonce,
     ) {
-        self.append_action(receipt_index);
+        self.append_action(
+            receipt_index,
+            Action::AddKeyWithFullAccess { public_key, nonce },
+        );
     }
 
     fn append_action_add_key_with_function_call(
         &mut self,
         receipt_index: logic::types::ReceiptIndex,
-        _public_key: near_crypto::PublicKey,
-        _nonce: near_primitives_core::types::Nonce,
-        _allowance: Option<Balance>,
-        _receiver_id: AccountId,
-        _method_names: Vec<Vec<u8>>,
+        public_key: near_crypto::PublicKey,
+        nonce: near_primitives_core::types::Nonce,
+        allowance: Option<Balance>,
+        receiver_id: AccountId,
+        method_names: Vec<Vec<u8>>,
     ) -> SResult<(), logic::VMLogicError> {
-        self.append_action(receipt_index);
+        let method_names = method_names
+            .into_iter()
+            .map(|name| String::from_utf8(name).map_err(|_| logic::HostError::InvalidMethodName))
+            .collect::<SResult<Vec<_>, _>>()?;
+        self.append_action(
+            receipt_index,
+            Action::AddKeyWithFunctionCall {
+                public_key,
+                nonce,
+                allowance,
+                receiver_id,
+                method_names,
+            },
+        );
         Ok(())
     }
 
     fn append_action_delete_key(
         &mut self,
         receipt_index: logic::types::ReceiptIndex,
-        _public_key: near_crypto::PublicKey,
+        public_key: near_crypto::PublicKey,
     ) {
-        self.append_action(receipt_index);
+        self.append_action(receipt_index, Action::DeleteKey { public_key });
     }
 
     fn append_action_delete_account(
         &mut self,
         receipt_index: logic::types::ReceiptIndex,
-        _beneficiary_id: AccountId,
+        beneficiary_id: AccountId,
     ) -> SResult<(), logic::VMLogicError> {
-        self.append_action(receipt_index);
+        self.append_action(receipt_index, Action::DeleteAccount { beneficiary_id });
         Ok(())
     }
 
@@ -396,7 +810,11 @@ impl External for DebugExternal {
             .receipts
             .get(receipt_index as usize)
             .expect("receipt index should have been returned by the runtime")
-            .receiver
+            .receiver_id
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
     }
 }
 
@@ -412,7 +830,7 @@ impl Context {
         Self(VMContext {
             current_account_id: alice.clone(),
             signer_account_id: bob.clone(),
-            signer_account_pk: CryptoHash::default().as_bytes().to_vec(),
+            signer_account_pk: serialize_public_key(&PublicKey::empty(KeyType::ED25519)),
             predecessor_account_id: bob,
             attached_deposit: 0,
             input: vec![],
@@ -440,18 +858,19 @@ impl Context {
         self
     }
 
-    pub fn attached_deposit(mut self, deposit: &str) -> Result<Self> {
-        self.0.attached_deposit = deposit.parse()?;
+    /// Results of the promises the call is a callback of, as a JSON list, e.g.
+    /// `[{"successful_json": {"amount": "1"}}, {"successful_base64": "AQ=="}, "failed"]`.
+    ///
+    /// `successful_json` results are passed to the contract as the JSON text, whereas
+    /// `successful_base64` ones are decoded first (e.g. for borsh serialized results.) `not_ready`
+    /// results can be given as well, even though nearcore never produces them.
+    pub fn promise_results(mut self, json: &str) -> Result<Self> {
+        self.0.promise_results = parse_promise_results(json).map_err(|e| JsError::new(&e))?;
         Ok(self)
     }
 
-    pub fn balance(mut self, balance: &str) -> Result<Self> {
-        self.0.account_balance = balance.parse()?;
-        Ok(self)
-    }
-
-    pub fn locked_balance(mut self, balance: &str) -> Result<Self> {
-        self.0.account_locked_balance = balance.parse()?;
+    pub fn attached_deposit(mut self, deposit: &str) -> Result<Self> {
+        self.0.attached_deposit = deposit.parse()?;
         Ok(self)
     }
 
@@ -465,11 +884,10 @@ impl Context {
         Ok(self)
     }
 
+    /// The public key the transaction has been signed with, e.g. `ed25519:...` or
+    /// `secp256k1:...`.
     pub fn signer_account_pk(mut self, key: &str) -> Result<Self> {
-        self.0.signer_account_pk = CryptoHash::from_str(key)
-            .map_err(|e| JsError::new(&e.to_string()))?
-            .as_bytes()
-            .to_vec();
+        self.0.signer_account_pk = serialize_public_key(&key.parse()?);
         Ok(self)
     }
 
@@ -500,740 +918,300 @@ impl Context {
             .to_vec();
         Ok(self)
     }
-}
-
-#[wasm_bindgen]
-pub struct Logic {
-    logic: logic::VMLogic,
-}
-
-type Result<T> = std::result::Result<T, JsError>;
-
-#[wasm_bindgen]
-impl Logic {
-    #[wasm_bindgen(constructor)]
-    pub fn new(context: Context, memory: js_sys::WebAssembly::Memory, ext: DebugExternal) -> Self {
-        let config_store = near_parameters::RuntimeConfigStore::new(None);
-        let config = config_store.get_config(near_primitives_core::version::PROTOCOL_VERSION);
-        let gas_counter = context.0.make_gas_counter(&config.wasm_config);
-        let result_state =
-            ExecutionResultState::new(&context.0, gas_counter, config.wasm_config.clone());
-        let ext = Box::new(ext);
-        Self {
-            logic: logic::VMLogic::new(
-                ext,
-                context.0,
-                config.fees.clone(),
-                result_state,
-                Box::new(memory),
-            ),
-        }
-    }
 
-    pub fn context(&self) -> Result<JsValue> {
-        self.logic
-            .context
-            .serialize(&js_serializer())
-            .map_err(Into::into)
-    }
-
-    pub fn outcome(&self) -> Result<JsValue> {
-        self.logic
-            .result_state
-            .clone()
-            .compute_outcome()
-            .serialize(&js_serializer())
-            .map_err(Into::into)
-    }
-
-    pub fn registers(&mut self) -> Result<JsValue> {
-        let s = js_serializer();
-        self.logic.registers().serialize(&s).map_err(Into::into)
-    }
-
-    pub fn fees_before_loading_executable(
-        &mut self,
-        method_name: &str,
-        code_len: u64,
-    ) -> Result<()> {
-        let config = self.logic.config().clone();
-        self.logic
-            .gas_counter()
-            .before_loading_executable(&config, method_name, code_len)
-            .map_err(|e| JsError::new(&e.to_string()))
-    }
-
-    pub fn fees_after_loading_executable(&mut self, code_len: u64) -> Result<()> {
-        let config = self.logic.config().clone();
-        self.logic
-            .gas_counter()
-            .after_loading_executable(&config, code_len)
-            .map_err(|e| JsError::new(&e.to_string()))
-    }
-
-    pub fn process_gas_limit(&mut self) -> JsError {
-        JsError::new(&self.logic.process_gas_limit().to_string())
-    }
-
-    pub fn finite_wasm_gas(&mut self, gas: u64) -> Result<()> {
-        self.logic.finite_wasm_gas(gas).map_err(Into::into)
-    }
-
-    pub fn finite_wasm_stack(&mut self, operand_size: u64, frame_size: u64) -> Result<()> {
-        self.logic
-            .finite_wasm_stack(operand_size, frame_size)
-            .map_err(Into::into)
-    }
-
-    pub fn finite_wasm_unstack(&mut self, operand_size: u64, frame_size: u64) -> Result<()> {
-        self.logic
-            .finite_wasm_unstack(operand_size, frame_size)
-            .map_err(Into::into)
-    }
-
-    pub fn read_register(&mut self, register_id: u64, ptr: u64) -> Result<()> {
-        self.logic
-            .read_register(register_id, ptr)
-            .map_err(Into::into)
-    }
-
-    pub fn register_len(&mut self, register_id: u64) -> Result<u64> {
-        self.logic.register_len(register_id).map_err(Into::into)
-    }
-
-    pub fn write_register(&mut self, register_id: u64, data_len: u64, data_ptr: u64) -> Result<()> {
-        self.logic
-            .write_register(register_id, data_len, data_ptr)
-            .map_err(Into::into)
-    }
-
-    pub fn current_account_id(&mut self, register_id: u64) -> Result<()> {
-        self.logic
-            .current_account_id(register_id)
-            .map_err(Into::into)
-    }
-
-    pub fn signer_account_id(&mut self, register_id: u64) -> Result<()> {
-        self.logic
-            .signer_account_id(register_id)
-            .map_err(Into::into)
-    }
-
-    pub fn signer_account_pk(&mut self, register_id: u64) -> Result<()> {
-        self.logic
-            .signer_account_pk(register_id)
-            .map_err(Into::into)
-    }
-
-    pub fn predecessor_account_id(&mut self, register_id: u64) -> Result<()> {
-        self.logic
-            .predecessor_account_id(register_id)
-            .map_err(Into::into)
-    }
-
-    pub fn input(&mut self, register_id: u64) -> Result<()> {
-        self.logic.input(register_id).map_err(Into::into)
-    }
-
-    pub fn block_index(&mut self) -> Result<u64> {
-        self.logic.block_index().map_err(Into::into)
-    }
-
-    pub fn block_timestamp(&mut self) -> Result<u64> {
-        self.logic.block_timestamp().map_err(Into::into)
-    }
-
-    pub fn epoch_height(&mut self) -> Result<EpochHeight> {
-        self.logic.epoch_height().map_err(Into::into)
-    }
-
-    pub fn validator_stake(
-        &mut self,
-        account_id_len: u64,
-        account_id_ptr: u64,
-        stake_ptr: u64,
-    ) -> Result<()> {
-        self.logic
-            .validator_stake(account_id_len, account_id_ptr, stake_ptr)
-            .map_err(Into::into)
-    }
-
-    pub fn validator_total_stake(&mut self, stake_ptr: u64) -> Result<()> {
-        self.logic
-            .validator_total_stake(stake_ptr)
-            .map_err(Into::into)
-    }
-
-    pub fn storage_usage(&mut self) -> Result<StorageUsage> {
-        self.logic.storage_usage().map_err(Into::into)
-    }
-
-    pub fn account_balance(&mut self, balance_ptr: u64) -> Result<()> {
-        self.logic.account_balance(balance_ptr).map_err(Into::into)
-    }
-
-    pub fn account_locked_balance(&mut self, balance_ptr: u64) -> Result<()> {
-        self.logic
-            .account_locked_balance(balance_ptr)
-            .map_err(Into::into)
-    }
-
-    pub fn attached_deposit(&mut self, balance_ptr: u64) -> Result<()> {
-        self.logic.attached_deposit(balance_ptr).map_err(Into::into)
-    }
-
-    pub fn prepaid_gas(&mut self) -> Result<Gas> {
-        self.logic.prepaid_gas().map_err(Into::into)
-    }
-
-    pub fn used_gas(&mut self) -> Result<Gas> {
-        self.logic.used_gas().map_err(Into::into)
-    }
-
-    pub fn alt_bn128_g1_multiexp(
-        &mut self,
-        value_len: u64,
-        value_ptr: u64,
-        register_id: u64,
-    ) -> Result<()> {
-        self.logic
-            .alt_bn128_g1_multiexp(value_len, value_ptr, register_id)
-            .map_err(Into::into)
-    }
-
-    pub fn alt_bn128_g1_sum(
-        &mut self,
-        value_len: u64,
-        value_ptr: u64,
-        register_id: u64,
-    ) -> Result<()> {
-        self.logic
-            .alt_bn128_g1_sum(value_len, value_ptr, register_id)
-            .map_err(Into::into)
-    }
-
-    pub fn alt_bn128_pairing_check(&mut self, value_len: u64, value_ptr: u64) -> Result<u64> {
-        self.logic
-            .alt_bn128_pairing_check(value_len, value_ptr)
-            .map_err(Into::into)
-    }
-
-    pub fn bls12381_p1_sum(&mut self, len: u64, ptr: u64, register_id: u64) -> Result<u64> {
-        self.logic
-            .bls12381_p1_sum(len, ptr, register_id)
-            .map_err(Into::into)
-    }
-
-    pub fn bls12381_p2_sum(&mut self, len: u64, ptr: u64, register_id: u64) -> Result<u64> {
-        self.logic
-            .bls12381_p2_sum(len, ptr, register_id)
-            .map_err(Into::into)
-    }
-
-    pub fn bls12381_g1_multiexp(&mut self, len: u64, ptr: u64, register_id: u64) -> Result<u64> {
-        self.logic
-            .bls12381_g1_multiexp(len, ptr, register_id)
-            .map_err(Into::into)
-    }
-
-    pub fn bls12381_g2_multiexp(&mut self, len: u64, ptr: u64, register_id: u64) -> Result<u64> {
-        self.logic
-            .bls12381_g2_multiexp(len, ptr, register_id)
-            .map_err(Into::into)
-    }
-
-    pub fn bls12381_map_fp_to_g1(&mut self, len: u64, ptr: u64, register_id: u64) -> Result<u64> {
-        self.logic
-            .bls12381_map_fp_to_g1(len, ptr, register_id)
-            .map_err(Into::into)
-    }
-
-    pub fn bls12381_map_fp2_to_g2(&mut self, len: u64, ptr: u64, register_id: u64) -> Result<u64> {
-        self.logic
-            .bls12381_map_fp2_to_g2(len, ptr, register_id)
-            .map_err(Into::into)
-    }
-
-    pub fn bls12381_p1_decompress(&mut self, len: u64, ptr: u64, register_id: u64) -> Result<u64> {
-        self.logic
-            .bls12381_p1_decompress(len, ptr, register_id)
-            .map_err(Into::into)
+    /// Execute the call as a view call (e.g. the `call_function` RPC query) burning at most
+    /// `max_gas_burnt`.
+    ///
+    /// Like nearcore, the call is made on behalf of the contract account itself, with an empty
+    /// signer public key and without any deposit or prepaid gas, regardless of the values given
+    /// to the other methods of the builder.
+    pub fn view(self, max_gas_burnt: &str) -> Result<Self> {
+        Ok(self.into_view(max_gas_burnt.parse()?))
     }
+}
 
-    pub fn bls12381_p2_decompress(&mut self, len: u64, ptr: u64, register_id: u64) -> Result<u64> {
-        self.logic
-            .bls12381_p2_decompress(len, ptr, register_id)
-            .map_err(Into::into)
+impl Context {
+    /// See [`Self::view`].
+    pub(crate) fn into_view(mut self, max_gas_burnt: Gas) -> Self {
+        self.0.view_config = Some(ViewConfig { max_gas_burnt });
+        self
     }
 
-    pub fn bls12381_pairing_check(&mut self, len: u64, ptr: u64) -> Result<u64> {
-        self.logic
-            .bls12381_pairing_check(len, ptr)
-            .map_err(Into::into)
+    /// Make a view call on behalf of the contract account itself, without any deposit or prepaid
+    /// gas. Done once the context is complete, so that it does not matter in which order the
+    /// methods of the builder have been called.
+    fn apply_view_config(&mut self) {
+        if self.0.view_config.is_none() {
+            return;
+        }
+        self.0.signer_account_id = self.0.current_account_id.clone();
+        self.0.predecessor_account_id = self.0.current_account_id.clone();
+        self.0.signer_account_pk = serialize_public_key(&PublicKey::empty(KeyType::ED25519));
+        self.0.attached_deposit = 0;
+        self.0.prepaid_gas = 0;
     }
 
-    pub fn random_seed(&mut self, register_id: u64) -> Result<()> {
-        self.logic.random_seed(register_id).map_err(Into::into)
+    pub(crate) fn vm_context(&self) -> &VMContext {
+        &self.0
     }
 
-    pub fn sha256(&mut self, value_len: u64, value_ptr: u64, register_id: u64) -> Result<()> {
-        self.logic
-            .sha256(value_len, value_ptr, register_id)
-            .map_err(Into::into)
+    /// The context being built, for the embedders that have already parsed the values.
+    pub(crate) fn vm_context_mut(&mut self) -> &mut VMContext {
+        &mut self.0
     }
+}
 
-    pub fn keccak256(&mut self, value_len: u64, value_ptr: u64, register_id: u64) -> Result<()> {
-        self.logic
-            .keccak256(value_len, value_ptr, register_id)
-            .map_err(Into::into)
-    }
+/// Serialize the `public_key` the way nearcore passes it to the contracts, i.e. with borsh.
+pub(crate) fn serialize_public_key(public_key: &PublicKey) -> Vec<u8> {
+    borsh::to_vec(public_key).expect("serializing a public key cannot fail")
+}
 
-    pub fn keccak512(&mut self, value_len: u64, value_ptr: u64, register_id: u64) -> Result<()> {
-        self.logic
-            .keccak512(value_len, value_ptr, register_id)
-            .map_err(Into::into)
-    }
+/// A promise result given to [`Context::promise_results`].
+#[serde_as]
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum PromiseResultValue {
+    SuccessfulJson(serde_json::Value),
+    SuccessfulBase64(#[serde_as(as = "serde_with::base64::Base64")] Vec<u8>),
+    Failed,
+    NotReady,
+}
 
-    pub fn ripemd160(&mut self, value_len: u64, value_ptr: u64, register_id: u64) -> Result<()> {
-        self.logic
-            .ripemd160(value_len, value_ptr, register_id)
-            .map_err(Into::into)
-    }
+/// Parse the promise results in the format [`Context::promise_results`] takes.
+pub(crate) fn parse_promise_results(json: &str) -> SResult<Arc<[PromiseResult]>, String> {
+    let values: Vec<PromiseResultValue> =
+        serde_json::from_str(json).map_err(|e| format!("invalid promise results: {e}"))?;
+    Ok(values
+        .into_iter()
+        .map(|value| match value {
+            PromiseResultValue::SuccessfulJson(value) => {
+                PromiseResult::Successful(value.to_string().into_bytes())
+            }
+            PromiseResultValue::SuccessfulBase64(data) => PromiseResult::Successful(data),
+            PromiseResultValue::Failed => PromiseResult::Failed,
+            PromiseResultValue::NotReady => PromiseResult::NotReady,
+        })
+        .collect())
+}
 
-    pub fn ecrecover(
-        &mut self,
-        hash_len: u64,
-        hash_ptr: u64,
-        sig_len: u64,
-        sig_ptr: u64,
-        v: u64,
-        malleability_flag: u64,
-        register_id: u64,
-    ) -> Result<u64> {
-        self.logic
-            .ecrecover(
-                hash_len,
-                hash_ptr,
-                sig_len,
-                sig_ptr,
-                v,
-                malleability_flag,
-                register_id,
-            )
-            .map_err(Into::into)
-    }
+#[wasm_bindgen]
+pub struct Logic {
+    /// Shared with the host functions handed out by [`Logic::imports`].
+    logic: Rc<RefCell<logic::VMLogic>>,
+    tracer: trace::Tracer,
+    /// The contract instance, once [`Logic::execute`] has instantiated it.
+    instance: RefCell<Option<js_sys::WebAssembly::Instance>>,
+    /// Why the execution has failed, see [`Self::abort`]. Host functions record their errors
+    /// here as well.
+    aborted: Rc<RefCell<Option<FunctionCallError>>>,
+}
 
-    pub fn ed25519_verify(
-        &mut self,
-        signature_len: u64,
-        signature_ptr: u64,
-        message_len: u64,
-        message_ptr: u64,
-        public_key_len: u64,
-        public_key_ptr: u64,
-    ) -> Result<u64> {
-        self.logic
-            .ed25519_verify(
-                signature_len,
-                signature_ptr,
-                message_len,
-                message_ptr,
-                public_key_len,
-                public_key_ptr,
-            )
-            .map_err(Into::into)
-    }
+type Result<T> = std::result::Result<T, JsError>;
 
-    pub fn gas(&mut self, opcodes: u32) -> Result<()> {
-        self.logic.gas_seen_from_wasm(opcodes).map_err(Into::into)
+impl Logic {
+    /// Construct the logic operating on the contract `memory`, with the runtime config the `ext`
+    /// has been constructed with.
+    ///
+    /// The view call overrides of the `context` are applied (see [`Context::view`]), and its
+    /// balances are replaced with those of the account in the world the `ext` has been
+    /// constructed with, and the storage usage with the one the `ext` computes from the contents
+    /// of the account, so that they are the same as on chain. So is the random seed, if the `ext`
+    /// derives it from the random value of the block.
+    pub(crate) fn with_memory(
+        mut context: Context,
+        memory: Box<dyn logic::MemoryLike>,
+        ext: DebugExternal,
+    ) -> Self {
+        let config = ext.config().clone();
+        context.apply_view_config();
+        (context.0.account_balance, context.0.account_locked_balance) = ext.balances;
+        context.0.storage_usage = ext.storage_usage();
+        if let Some(random_seed) = ext.random_seed() {
+            context.0.random_seed = random_seed;
+        }
+        let gas_counter = context.0.make_gas_counter(config.wasm_config());
+        let result_state =
+            ExecutionResultState::new(&context.0, gas_counter, config.wasm_config().clone());
+        let ext = Box::new(ext);
+        let logic =
+            logic::VMLogic::new(ext, context.0, config.fees().clone(), result_state, memory);
+        Self {
+            logic: Rc::new(RefCell::new(logic)),
+            tracer: Default::default(),
+            instance: Default::default(),
+            aborted: Default::default(),
+        }
     }
 
-    pub fn burn_gas(&mut self, gas: Gas) -> Result<()> {
-        self.logic.burn_gas(gas).map_err(Into::into)
+    pub(crate) fn tracer(&self) -> &trace::Tracer {
+        &self.tracer
     }
 
-    pub fn promise_create(
-        &mut self,
-        account_id_len: u64,
-        account_id_ptr: u64,
-        method_name_len: u64,
-        method_name_ptr: u64,
-        arguments_len: u64,
-        arguments_ptr: u64,
-        amount_ptr: u64,
-        gas: Gas,
-    ) -> Result<u64> {
-        self.logic
-            .promise_create(
-                account_id_len,
-                account_id_ptr,
-                method_name_len,
-                method_name_ptr,
-                arguments_len,
-                arguments_ptr,
-                amount_ptr,
-                gas,
-            )
-            .map_err(Into::into)
+    pub(crate) fn vm_logic(&self) -> Ref<'_, logic::VMLogic> {
+        self.logic.borrow()
     }
 
-    pub fn promise_then(
-        &mut self,
-        promise_idx: u64,
-        account_id_len: u64,
-        account_id_ptr: u64,
-        method_name_len: u64,
-        method_name_ptr: u64,
-        arguments_len: u64,
-        arguments_ptr: u64,
-        amount_ptr: u64,
-        gas: u64,
-    ) -> Result<u64> {
-        self.logic
-            .promise_then(
-                promise_idx,
-                account_id_len,
-                account_id_ptr,
-                method_name_len,
-                method_name_ptr,
-                arguments_len,
-                arguments_ptr,
-                amount_ptr,
-                gas,
-            )
-            .map_err(Into::into)
+    pub(crate) fn vm_logic_mut(&self) -> std::cell::RefMut<'_, logic::VMLogic> {
+        self.logic.borrow_mut()
     }
 
-    pub fn promise_and(
-        &mut self,
-        promise_idx_ptr: u64,
-        promise_idx_count: u64,
-    ) -> Result<PromiseIndex> {
-        self.logic
-            .promise_and(promise_idx_ptr, promise_idx_count)
-            .map_err(Into::into)
+    /// The outcome of the execution so far, as nearcore would report it.
+    pub fn compute_outcome(&self) -> logic::VMOutcome {
+        let mut outcome = self.vm_logic().result_state.clone().compute_outcome();
+        outcome.aborted = self.aborted.borrow().clone();
+        outcome
     }
 
-    pub fn promise_batch_create(
-        &mut self,
-        account_id_len: u64,
-        account_id_ptr: u64,
-    ) -> Result<u64> {
-        self.logic
-            .promise_batch_create(account_id_len, account_id_ptr)
-            .map_err(Into::into)
+    /// Record that the execution has failed with the `error`, unless it has failed already, and
+    /// return the error the execution has failed with.
+    pub(crate) fn abort(&self, error: FunctionCallError) -> FunctionCallError {
+        self.aborted.borrow_mut().get_or_insert(error).clone()
     }
 
-    pub fn promise_batch_then(
-        &mut self,
-        promise_idx: u64,
-        account_id_len: u64,
-        account_id_ptr: u64,
-    ) -> Result<u64> {
-        self.logic
-            .promise_batch_then(promise_idx, account_id_len, account_id_ptr)
-            .map_err(Into::into)
+    /// Like [`Self::abort`], for an `error` the contract has failed with while it was executing.
+    ///
+    /// Running out of gas is reported the way nearcore reports it, which depends on which of the
+    /// gas limits has been reached.
+    pub(crate) fn abort_execution(&self, error: FunctionCallError) -> FunctionCallError {
+        if let Some(aborted) = &*self.aborted.borrow() {
+            return aborted.clone();
+        }
+        let error = match error {
+            FunctionCallError::HostError(HostError::GasExceeded) => {
+                FunctionCallError::HostError(self.vm_logic_mut().process_gas_limit())
+            }
+            error => error,
+        };
+        self.abort(error)
     }
 
-    pub fn promise_batch_action_create_account(&mut self, promise_idx: u64) -> Result<()> {
-        self.logic
-            .promise_batch_action_create_account(promise_idx)
-            .map_err(Into::into)
+    /// Like [`Self::abort_execution`], for an `error` thrown by the browser while instantiating or
+    /// calling the contract. The errors thrown by host functions have been recorded already.
+    pub(crate) fn fail(&self, error: &JsValue) -> JsValue {
+        let recorded = self.aborted.borrow_mut().take();
+        let error = recorded.unwrap_or_else(|| function_call_error_from_js(error));
+        function_call_error_to_js(&self.abort_execution(error))
     }
 
-    pub fn promise_batch_action_deploy_contract(
-        &mut self,
-        promise_idx: u64,
+    /// Charge the fees nearcore charges for loading the contract before it is loaded, which
+    /// includes checking the `method_name`.
+    pub(crate) fn before_loading_executable(
+        &self,
+        method_name: &str,
         code_len: u64,
-        code_ptr: u64,
-    ) -> Result<()> {
-        self.logic
-            .promise_batch_action_deploy_contract(promise_idx, code_len, code_ptr)
-            .map_err(Into::into)
-    }
-
-    pub fn promise_batch_action_function_call(
-        &mut self,
-        promise_idx: u64,
-        method_name_len: u64,
-        method_name_ptr: u64,
-        arguments_len: u64,
-        arguments_ptr: u64,
-        amount_ptr: u64,
-        gas: Gas,
-    ) -> Result<()> {
-        self.logic
-            .promise_batch_action_function_call(
-                promise_idx,
-                method_name_len,
-                method_name_ptr,
-                arguments_len,
-                arguments_ptr,
-                amount_ptr,
-                gas,
-            )
-            .map_err(Into::into)
-    }
-
-    pub fn promise_batch_action_function_call_weight(
-        &mut self,
-        promise_idx: u64,
-        method_name_len: u64,
-        method_name_ptr: u64,
-        arguments_len: u64,
-        arguments_ptr: u64,
-        amount_ptr: u64,
-        gas: Gas,
-        gas_weight: u64,
-    ) -> Result<()> {
-        self.logic
-            .promise_batch_action_function_call_weight(
-                promise_idx,
-                method_name_len,
-                method_name_ptr,
-                arguments_len,
-                arguments_ptr,
-                amount_ptr,
-                gas,
-                gas_weight,
-            )
-            .map_err(Into::into)
-    }
-
-    pub fn promise_batch_action_transfer(
-        &mut self,
-        promise_idx: u64,
-        amount_ptr: u64,
-    ) -> Result<()> {
-        self.logic
-            .promise_batch_action_transfer(promise_idx, amount_ptr)
-            .map_err(Into::into)
-    }
-
-    pub fn promise_batch_action_stake(
-        &mut self,
-        promise_idx: u64,
-        amount_ptr: u64,
-        public_key_len: u64,
-        public_key_ptr: u64,
-    ) -> Result<()> {
-        self.logic
-            .promise_batch_action_stake(promise_idx, amount_ptr, public_key_len, public_key_ptr)
-            .map_err(Into::into)
-    }
-
-    pub fn promise_batch_action_add_key_with_full_access(
-        &mut self,
-        promise_idx: u64,
-        public_key_len: u64,
-        public_key_ptr: u64,
-        nonce: u64,
-    ) -> Result<()> {
-        self.logic
-            .promise_batch_action_add_key_with_full_access(
-                promise_idx,
-                public_key_len,
-                public_key_ptr,
-                nonce,
-            )
-            .map_err(Into::into)
-    }
-
-    pub fn promise_batch_action_add_key_with_function_call(
-        &mut self,
-        promise_idx: u64,
-        public_key_len: u64,
-        public_key_ptr: u64,
-        nonce: u64,
-        allowance_ptr: u64,
-        receiver_id_len: u64,
-        receiver_id_ptr: u64,
-        method_names_len: u64,
-        method_names_ptr: u64,
-    ) -> Result<()> {
-        self.logic
-            .promise_batch_action_add_key_with_function_call(
-                promise_idx,
-                public_key_len,
-                public_key_ptr,
-                nonce,
-                allowance_ptr,
-                receiver_id_len,
-                receiver_id_ptr,
-                method_names_len,
-                method_names_ptr,
-            )
-            .map_err(Into::into)
-    }
-
-    pub fn promise_batch_action_delete_key(
-        &mut self,
-        promise_idx: u64,
-        public_key_len: u64,
-        public_key_ptr: u64,
-    ) -> Result<()> {
-        self.logic
-            .promise_batch_action_delete_key(promise_idx, public_key_len, public_key_ptr)
-            .map_err(Into::into)
-    }
-
-    pub fn promise_batch_action_delete_account(
-        &mut self,
-        promise_idx: u64,
-        beneficiary_id_len: u64,
-        beneficiary_id_ptr: u64,
-    ) -> Result<()> {
-        self.logic
-            .promise_batch_action_delete_account(
-                promise_idx,
-                beneficiary_id_len,
-                beneficiary_id_ptr,
-            )
-            .map_err(Into::into)
-    }
-
-    pub fn promise_yield_create(
-        &mut self,
-        method_name_len: u64,
-        method_name_ptr: u64,
-        arguments_len: u64,
-        arguments_ptr: u64,
-        gas: Gas,
-        gas_weight: u64,
-        register_id: u64,
-    ) -> Result<u64> {
-        self.logic
-            .promise_yield_create(
-                method_name_len,
-                method_name_ptr,
-                arguments_len,
-                arguments_ptr,
-                gas,
-                gas_weight,
-                register_id,
-            )
-            .map_err(Into::into)
-    }
-
-    pub fn promise_yield_resume(
-        &mut self,
-        data_id_len: u64,
-        data_id_ptr: u64,
-        payload_len: u64,
-        payload_ptr: u64,
-    ) -> Result<u32> {
-        self.logic
-            .promise_yield_resume(data_id_len, data_id_ptr, payload_len, payload_ptr)
-            .map_err(Into::into)
-    }
-
-    pub fn promise_results_count(&mut self) -> Result<u64> {
-        self.logic.promise_results_count().map_err(Into::into)
-    }
-
-    pub fn promise_result(&mut self, result_idx: u64, register_id: u64) -> Result<u64> {
-        self.logic
-            .promise_result(result_idx, register_id)
-            .map_err(Into::into)
-    }
-
-    pub fn promise_return(&mut self, promise_idx: u64) -> Result<()> {
-        self.logic.promise_return(promise_idx).map_err(Into::into)
-    }
-
-    pub fn value_return(&mut self, value_len: u64, value_ptr: u64) -> Result<()> {
-        self.logic
-            .value_return(value_len, value_ptr)
-            .map_err(Into::into)
-    }
-
-    pub fn get_utf8_string_free(&mut self, len: u64, ptr: u64) -> Result<String> {
-        self.logic
-            .get_utf8_string_free(len, ptr)
-            .map_err(Into::into)
-    }
-
-    pub fn log_utf8(&mut self, len: u64, ptr: u64) -> Result<()> {
-        self.logic.log_utf8(len, ptr).map_err(Into::into)
-    }
-
-    pub fn log_utf16(&mut self, len: u64, ptr: u64) -> Result<()> {
-        self.logic.log_utf16(len, ptr).map_err(Into::into)
-    }
-
-    pub fn abort(&mut self, msg_ptr: u32, filename_ptr: u32, line: u32, col: u32) -> Result<()> {
-        self.logic
-            .abort(msg_ptr, filename_ptr, line, col)
-            .map_err(Into::into)
+    ) -> SResult<(), FunctionCallError> {
+        let result = {
+            let mut logic = self.vm_logic_mut();
+            let config = logic.config().clone();
+            logic
+                .gas_counter()
+                .before_loading_executable(&config, method_name, code_len)
+        };
+        result.map_err(|e| self.abort(e))
+    }
+
+    /// Charge the fees that used to be charged for loading the contract once it is loaded in
+    /// older protocol versions.
+    pub(crate) fn after_loading_executable(&self, code_len: u64) -> SResult<(), FunctionCallError> {
+        let result = {
+            let mut logic = self.vm_logic_mut();
+            let config = logic.config().clone();
+            logic
+                .gas_counter()
+                .after_loading_executable(&config, code_len)
+        };
+        result.map_err(|e| self.abort(e))
+    }
+
+    pub(crate) fn external(&self) -> Ref<'_, DebugExternal> {
+        Ref::map(self.vm_logic(), |logic| {
+            logic
+                .ext()
+                .as_any()
+                .downcast_ref()
+                .expect("Logic is always constructed with a DebugExternal")
+        })
     }
+}
 
-    pub fn panic_utf8(&mut self, len: u64, ptr: u64) -> Result<()> {
-        self.logic.panic_utf8(len, ptr).map_err(Into::into)
+#[wasm_bindgen]
+impl Logic {
+    /// Construct the logic with the runtime config the `ext` has been constructed with.
+    #[wasm_bindgen(constructor)]
+    pub fn new(context: Context, memory: js_sys::WebAssembly::Memory, ext: DebugExternal) -> Self {
+        Self::with_memory(context, Box::new(memory), ext)
     }
 
-    pub fn panic(&mut self) -> Result<()> {
-        self.logic.panic().map_err(Into::into)
+    /// Record the host functions called by the contract, see [`Self::trace`]. Each call is also
+    /// printed to the console as it is made.
+    pub fn with_tracing(self) -> Self {
+        self.tracer.enable();
+        self
     }
 
-    pub fn storage_write(
-        &mut self,
-        key_len: u64,
-        key_ptr: u64,
-        value_len: u64,
-        value_ptr: u64,
-        register_id: u64,
-    ) -> Result<u64> {
-        self.logic
-            .storage_write(key_len, key_ptr, value_len, value_ptr, register_id)
+    /// The host functions called so far, with their decoded arguments, results and the gas they
+    /// have charged. Empty unless tracing has been enabled with [`Self::with_tracing`].
+    pub fn trace(&self) -> Result<JsValue> {
+        self.tracer
+            .calls()
+            .serialize(&js_serializer())
             .map_err(Into::into)
     }
 
-    pub fn storage_read(&mut self, key_len: u64, key_ptr: u64, register_id: u64) -> Result<u64> {
-        self.logic
-            .storage_read(key_len, key_ptr, register_id)
+    pub fn context(&self) -> Result<JsValue> {
+        self.vm_logic()
+            .context
+            .serialize(&js_serializer())
             .map_err(Into::into)
     }
 
-    pub fn storage_remove(&mut self, key_len: u64, key_ptr: u64, register_id: u64) -> Result<u64> {
-        self.logic
-            .storage_remove(key_len, key_ptr, register_id)
+    pub fn outcome(&self) -> Result<JsValue> {
+        self.compute_outcome()
+            .serialize(&js_serializer())
             .map_err(Into::into)
     }
 
-    pub fn storage_has_key(&mut self, key_len: u64, key_ptr: u64) -> Result<u64> {
-        self.logic
-            .storage_has_key(key_len, key_ptr)
+    /// Receipts created during the execution, along with all of their actions.
+    pub fn receipts(&self) -> Result<JsValue> {
+        self.external()
+            .receipts()
+            .serialize(&js_serializer())
             .map_err(Into::into)
     }
 
-    pub fn storage_iter_prefix(&mut self, prefix_len: u64, prefix_ptr: u64) -> Result<u64> {
-        self.logic
-            .storage_iter_prefix(prefix_len, prefix_ptr)
+    /// Storage entries of the contract added, modified and removed by the execution so far.
+    pub fn storage_diff(&self) -> Result<JsValue> {
+        self.external()
+            .storage_diff()
+            .serialize(&js_serializer())
             .map_err(Into::into)
     }
 
-    pub fn storage_iter_range(
-        &mut self,
-        start_len: u64,
-        start_ptr: u64,
-        end_len: u64,
-        end_ptr: u64,
-    ) -> Result<u64> {
-        self.logic
-            .storage_iter_range(start_len, start_ptr, end_len, end_ptr)
+    pub fn registers(&mut self) -> Result<JsValue> {
+        let s = js_serializer();
+        self.vm_logic_mut()
+            .registers()
+            .serialize(&s)
             .map_err(Into::into)
     }
+}
 
-    pub fn storage_iter_next(
-        &mut self,
-        iterator_id: u64,
-        key_register_id: u64,
-        value_register_id: u64,
-    ) -> Result<u64> {
-        self.logic
-            .storage_iter_next(iterator_id, key_register_id, value_register_id)
-            .map_err(Into::into)
-    }
+/// Create the memory to be imported by contracts prepared with the `config`.
+#[wasm_bindgen]
+pub fn create_memory(config: &RuntimeConfig) -> SResult<js_sys::WebAssembly::Memory, JsValue> {
+    let limits = &config.wasm_config().limit_config;
+    let descriptor = js_sys::Object::new();
+    js_sys::Reflect::set(
+        &descriptor,
+        &"initial".into(),
+        &limits.initial_memory_pages.into(),
+    )?;
+    js_sys::Reflect::set(
+        &descriptor,
+        &"maximum".into(),
+        &limits.max_memory_pages.into(),
+    )?;
+    js_sys::WebAssembly::Memory::new(&descriptor)
 }
 
 impl logic::MemoryLike for js_sys::WebAssembly::Memory {
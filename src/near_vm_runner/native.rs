@@ -0,0 +1,316 @@
+//! Execution of contracts outside of the browser, on top of the [`wasmi`] interpreter.
+//!
+//! The contracts are prepared and instrumented the same way they are for the browser and are
+//! linked to the same [`VMLogic`](super::logic::VMLogic) host functions, so that executions
+//! behave the same way in both environments.
+use super::execute::{compile_error, resolve_method};
+use super::imports::{
+    call_host_function, for_each_host_function, for_each_instrumentation_function,
+    host_function_enabled, is_traced,
+};
+use super::logic::errors::{FunctionCallError, MethodResolveError, VMRunnerError, WasmTrap};
+use super::logic::{MemSlice, MemoryLike, VMLogicError};
+use super::trace::{PendingCall, RawArg, ReturnValue};
+use super::{Context, DebugExternal, Logic};
+use std::borrow::Cow;
+use std::cell::Cell;
+use std::rc::Rc;
+
+impl wasmi::core::HostError for VMLogicError {}
+
+/// Why an execution has failed.
+///
+/// The [`FunctionCallError`]s are also recorded in the `aborted` field of the outcome, just like
+/// nearcore would record them.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("{0}")]
+    FunctionCall(FunctionCallError),
+    /// The contract could not be prepared, with the details of why.
+    #[error("{message}")]
+    Prepare {
+        function_call_error: FunctionCallError,
+        message: String,
+    },
+    #[error("{0}")]
+    Runner(VMRunnerError),
+}
+
+fn wasm_trap(error: &wasmi::Error) -> WasmTrap {
+    use wasmi::core::TrapCode;
+    match error.as_trap_code() {
+        Some(TrapCode::UnreachableCodeReached) => WasmTrap::Unreachable,
+        Some(TrapCode::MemoryOutOfBounds) => WasmTrap::MemoryOutOfBounds,
+        Some(TrapCode::TableOutOfBounds) => WasmTrap::CallIndirectOOB,
+        Some(TrapCode::IndirectCallToNull) => WasmTrap::IndirectCallToNull,
+        Some(
+            TrapCode::IntegerDivisionByZero
+            | TrapCode::IntegerOverflow
+            | TrapCode::BadConversionToInteger,
+        ) => WasmTrap::IllegalArithmetic,
+        Some(TrapCode::StackOverflow) => WasmTrap::StackOverflow,
+        Some(TrapCode::BadSignature) => WasmTrap::IncorrectCallIndirectSignature,
+        _ => WasmTrap::GenericTrap,
+    }
+}
+
+/// The contract memory as seen by the `VMLogic`.
+///
+/// The memory is owned by the `wasmi::Store`, which also owns the `VMLogic`, so the logic cannot
+/// hold on to a reference to the memory. Instead the location of the memory is set for the duration
+/// of every host function call.
+#[derive(Clone)]
+struct NativeMemory(Rc<Cell<(*mut u8, usize)>>);
+
+impl NativeMemory {
+    fn new() -> Self {
+        Self(Rc::new(Cell::new((std::ptr::null_mut(), 0))))
+    }
+
+    fn set(&self, data: &mut [u8]) {
+        self.0.set((data.as_mut_ptr(), data.len()));
+    }
+
+    fn clear(&self) {
+        self.0.set((std::ptr::null_mut(), 0));
+    }
+
+    fn data(&self) -> &[u8] {
+        let (ptr, len) = self.0.get();
+        if ptr.is_null() {
+            return &[];
+        }
+        // SAFETY: the pointer is only set for the duration of a host function call, during which
+        // the memory is exclusively borrowed for the host function.
+        unsafe { std::slice::from_raw_parts(ptr, len) }
+    }
+
+    fn data_mut(&mut self) -> &mut [u8] {
+        let (ptr, len) = self.0.get();
+        if ptr.is_null() {
+            return &mut [];
+        }
+        // SAFETY: see `data`.
+        unsafe { std::slice::from_raw_parts_mut(ptr, len) }
+    }
+}
+
+impl MemoryLike for NativeMemory {
+    fn fits_memory(&self, slice: MemSlice) -> Result<(), ()> {
+        let end = slice.end::<usize>()?;
+        if end <= self.data().len() {
+            Ok(())
+        } else {
+            Err(())
+        }
+    }
+
+    fn view_memory(&self, slice: MemSlice) -> Result<Cow<[u8]>, ()> {
+        let range = slice.range::<usize>()?;
+        self.data().get(range).map(Cow::Borrowed).ok_or(())
+    }
+
+    fn read_memory(&self, offset: u64, buffer: &mut [u8]) -> Result<(), ()> {
+        let start = usize::try_from(offset).map_err(|_| ())?;
+        let end = start.checked_add(buffer.len()).ok_or(())?;
+        buffer.copy_from_slice(self.data().get(start..end).ok_or(())?);
+        Ok(())
+    }
+
+    fn write_memory(&mut self, offset: u64, buffer: &[u8]) -> Result<(), ()> {
+        let start = usize::try_from(offset).map_err(|_| ())?;
+        let end = start.checked_add(buffer.len()).ok_or(())?;
+        self.data_mut()
+            .get_mut(start..end)
+            .ok_or(())?
+            .copy_from_slice(buffer);
+        Ok(())
+    }
+}
+
+struct HostState {
+    logic: Logic,
+    memory: NativeMemory,
+}
+
+/// Execute the `method_name` of the contract `code`.
+///
+/// The [`Logic`] is returned regardless of whether the execution succeeded, so that the outcome,
+/// logs, receipts and such can be inspected either way.
+pub fn run(
+    code: &[u8],
+    method_name: &str,
+    context: Context,
+    ext: DebugExternal,
+) -> (Logic, Result<(), Error>) {
+    execute(code, method_name, context, ext, false)
+}
+
+/// Like [`run`], but also record the host functions called by the contract. See
+/// [`Logic::trace`].
+pub fn run_traced(
+    code: &[u8],
+    method_name: &str,
+    context: Context,
+    ext: DebugExternal,
+) -> (Logic, Result<(), Error>) {
+    execute(code, method_name, context, ext, true)
+}
+
+fn execute(
+    code: &[u8],
+    method_name: &str,
+    context: Context,
+    ext: DebugExternal,
+    tracing: bool,
+) -> (Logic, Result<(), Error>) {
+    let config = ext.config().clone();
+    let memory = NativeMemory::new();
+    let mut logic = Logic::with_memory(context, Box::new(memory.clone()), ext);
+    if tracing {
+        logic = logic.with_tracing();
+    }
+    let engine = engine(config.wasm_config());
+    let mut store = wasmi::Store::new(&engine, HostState { logic, memory });
+    let result = instantiate_and_call(&mut store, code, method_name, &config);
+    (store.into_data().logic, result)
+}
+
+/// The engine to execute contracts prepared with the `config` with.
+///
+/// The stack of the contracts is limited to `max_stack_height` by the finite-wasm instrumentation,
+/// like in nearcore, so the limits of wasmi are set above anything the instrumentation allows.
+/// Otherwise deep recursion would trap in wasmi before running out of the stack nearcore provides.
+fn engine(config: &near_parameters::vm::Config) -> wasmi::Engine {
+    // The instrumentation accounts at least 64 bytes for every function activation, see
+    // `SimpleMaxStackCfg` in `prepare.rs`.
+    let max_recursion_depth = config.limit_config.max_stack_height as usize / 64 + 1;
+    let limits = wasmi::StackLimits::new(1024, usize::MAX, max_recursion_depth)
+        .expect("the initial value stack height is below the maximum");
+    let mut wasmi_config = wasmi::Config::default();
+    wasmi_config.set_stack_limits(limits);
+    wasmi::Engine::new(&wasmi_config)
+}
+
+/// Execute the contract, recording the reason for any failure into the `Logic` of the `store`.
+fn instantiate_and_call(
+    store: &mut wasmi::Store<HostState>,
+    code: &[u8],
+    method_name: &str,
+    config: &super::RuntimeConfig,
+) -> Result<(), Error> {
+    let abort = |store: &wasmi::Store<HostState>, error| {
+        Error::FunctionCall(store.data().logic.abort(error))
+    };
+    let wasm_config = config.wasm_config();
+    let code_len = code.len() as u64;
+    store
+        .data()
+        .logic
+        .before_loading_executable(method_name, code_len)
+        .map_err(Error::FunctionCall)?;
+    let prepared =
+        crate::prepare::prepare_contract(code, wasm_config).map_err(|e| Error::Prepare {
+            function_call_error: store.data().logic.abort(e.function_call_error.clone()),
+            message: e.to_string(),
+        })?;
+    let module = wasmi::Module::new(store.engine(), &prepared[..])
+        .map_err(|e| abort(store, compile_error(e.to_string())))?;
+    let limits = &wasm_config.limit_config;
+    let memory = wasmi::MemoryType::new(limits.initial_memory_pages, Some(limits.max_memory_pages))
+        .map_err(wasmi::Error::from)
+        .and_then(|memory_type| wasmi::Memory::new(&mut *store, memory_type))
+        .map_err(|e| abort(store, link_error(e)))?;
+    let mut linker = wasmi::Linker::new(store.engine());
+    linker
+        .define("env", "memory", memory)
+        .and_then(|linker| link(linker, memory, wasm_config))
+        .map_err(|e| abort(store, link_error(e.into())))?;
+    let instance = linker
+        .instantiate(&mut *store, &module)
+        .map_err(|e| abort(store, link_error(e)))?
+        .start(&mut *store)
+        .map_err(|e| execution_error(store, e))?;
+    store
+        .data()
+        .logic
+        .after_loading_executable(code_len)
+        .map_err(Error::FunctionCall)?;
+    resolve_method(code, method_name)
+        .map_err(|e| abort(store, FunctionCallError::MethodResolveError(e)))?;
+    let method = instance
+        .get_typed_func::<(), ()>(&*store, method_name)
+        .map_err(|_| {
+            let error = MethodResolveError::MethodInvalidSignature;
+            abort(store, FunctionCallError::MethodResolveError(error))
+        })?;
+    method
+        .call(&mut *store, ())
+        .map_err(|e| execution_error(store, e))
+}
+
+fn link_error(error: wasmi::Error) -> FunctionCallError {
+    FunctionCallError::LinkError {
+        msg: error.to_string(),
+    }
+}
+
+/// Record the `error` the contract has failed with while it was executing.
+fn execution_error(store: &wasmi::Store<HostState>, error: wasmi::Error) -> Error {
+    let logic = &store.data().logic;
+    let error = match error.downcast_ref::<VMLogicError>().cloned() {
+        Some(error) => match FunctionCallError::try_from(error) {
+            Ok(error) => error,
+            Err(error) => return Error::Runner(error),
+        },
+        None => FunctionCallError::WasmTrap(wasm_trap(&error)),
+    };
+    Error::FunctionCall(logic.abort_execution(error))
+}
+
+/// Define all of the host functions enabled by the `config` in the `linker`.
+fn link(
+    linker: &mut wasmi::Linker<HostState>,
+    memory: wasmi::Memory,
+    config: &near_parameters::vm::Config,
+) -> Result<(), wasmi::errors::LinkerError> {
+    macro_rules! link_host_functions {
+        ($module:literal; $(
+            $(#[$feature:ident])?
+            $name:ident $(as $method:ident)? <[ $($arg:ident : $ty:ident),* ] -> [ $($ret:ident),* ]>,
+        )*) => {
+            $(
+                if host_function_enabled!(config, $($feature)?) {
+                    let traced = is_traced($module);
+                    linker.func_wrap(
+                        $module,
+                        stringify!($name),
+                        move |mut caller: wasmi::Caller<'_, HostState>, $($arg: $ty),*|
+                            -> Result<($($ret),*), wasmi::Error>
+                        {
+                            let (data, state) = memory.data_and_store_mut(&mut caller);
+                            state.memory.set(data);
+                            let mut logic = state.logic.vm_logic_mut();
+                            let pending = PendingCall::begin(
+                                traced.then(|| state.logic.tracer()),
+                                &mut logic,
+                                stringify!($name),
+                                &[$((stringify!($arg), RawArg::raw(&$arg))),*],
+                            );
+                            let result = call_host_function!(logic, $name $(as $method)?, [$($arg),*]);
+                            if let Some(pending) = pending {
+                                pending.end(&mut logic, result.as_ref().map(ReturnValue::traced));
+                            }
+                            drop(logic);
+                            state.memory.clear();
+                            result.map_err(wasmi::Error::host)
+                        },
+                    )?;
+                }
+            )*
+        };
+    }
+    for_each_host_function!(link_host_functions);
+    for_each_instrumentation_function!(link_host_functions);
+    Ok(())
+}
@@ -0,0 +1,91 @@
+//! Receipts and actions created by a contract during its execution.
+//!
+//! These mirror the `ActionReceipt` and `Action` types of nearcore closely enough for the debugger
+//! to be able to execute them afterwards.
+use super::logic::types::ReceiptIndex;
+use near_crypto::PublicKey;
+use near_primitives_core::hash::CryptoHash;
+use near_primitives_core::types::{AccountId, Balance, Gas, Nonce};
+use serde_with::serde_as;
+
+/// Where the result of a receipt execution should be sent.
+#[derive(Clone, serde::Serialize)]
+pub(crate) struct DataReceiver {
+    pub(crate) data_id: CryptoHash,
+    pub(crate) receiver_id: AccountId,
+}
+
+#[serde_as]
+#[derive(Clone, serde::Serialize)]
+pub(crate) enum Action {
+    CreateAccount,
+    DeployContract {
+        code_hash: CryptoHash,
+        #[serde(skip)]
+        code: Vec<u8>,
+    },
+    FunctionCall {
+        method_name: String,
+        #[serde_as(as = "serde_with::base64::Base64")]
+        args: Vec<u8>,
+        deposit: Balance,
+        gas: Gas,
+        gas_weight: u64,
+    },
+    Transfer {
+        deposit: Balance,
+    },
+    Stake {
+        stake: Balance,
+        public_key: PublicKey,
+    },
+    AddKeyWithFullAccess {
+        public_key: PublicKey,
+        nonce: Nonce,
+    },
+    AddKeyWithFunctionCall {
+        public_key: PublicKey,
+        nonce: Nonce,
+        allowance: Option<Balance>,
+        receiver_id: AccountId,
+        method_names: Vec<String>,
+    },
+    DeleteKey {
+        public_key: PublicKey,
+    },
+    DeleteAccount {
+        beneficiary_id: AccountId,
+    },
+}
+
+#[derive(Clone, serde::Serialize)]
+pub(crate) struct Receipt {
+    pub(crate) receiver_id: AccountId,
+    /// Receipts (created by the same execution) whose results this receipt depends on.
+    pub(crate) receipt_indices: Vec<ReceiptIndex>,
+    /// Data this receipt waits for before it can be executed.
+    pub(crate) input_data_ids: Vec<CryptoHash>,
+    /// Receivers of the result of the last action in this receipt.
+    pub(crate) output_data_receivers: Vec<DataReceiver>,
+    pub(crate) actions: Vec<Action>,
+    pub(crate) is_promise_yield: bool,
+}
+
+impl Receipt {
+    pub(crate) fn new(receiver_id: AccountId) -> Self {
+        Self {
+            receiver_id,
+            receipt_indices: vec![],
+            input_data_ids: vec![],
+            output_data_receivers: vec![],
+            actions: vec![],
+            is_promise_yield: false,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct DataReceipt {
+    pub(crate) data_id: CryptoHash,
+    pub(crate) data: Option<Vec<u8>>,
+}
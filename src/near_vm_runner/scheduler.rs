@@ -0,0 +1,1011 @@
+//! Execution of the receipts produced by a contract call.
+//!
+//! The scheduler keeps the receipts that are yet to be executed along with the data they are
+//! waiting for. Executing the wasm itself remains a responsibility of the embedder: it asks the
+//! scheduler for the [`ScheduledCall`] that is ready to run next, runs it and then reports the
+//! [`Logic`] back so that the outcome can be routed to the dependent receipts the same way the
+//! nearcore runtime would route `DataReceipt`s.
+use super::logic::types::{PromiseResult, ReturnData};
+use super::logic::VMContext;
+use super::receipts::{Action, DataReceiver, Receipt};
+use super::staking::{check_storage_stake, LackBalanceForState};
+use super::world::{AccessKey, AccessKeyPermission, Account, YieldedReceipt};
+use super::{
+    create_action_hash, create_receipt_id_from_receipt_id, ChainHashes, Context, DebugExternal,
+    Logic, Result, RuntimeConfig, World,
+};
+use near_primitives_core::hash::CryptoHash;
+use near_primitives_core::types::{AccountId, Balance, BlockHeight, EpochHeight};
+use serde::Serialize as _;
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::Arc;
+use wasm_bindgen::prelude::*;
+
+pub(crate) struct PendingReceipt {
+    receipt_id: CryptoHash,
+    predecessor_id: AccountId,
+    pub(crate) receipt: Receipt,
+}
+
+/// A receipt that is currently being executed, one action at a time.
+struct InFlight {
+    pending: PendingReceipt,
+    promise_results: Arc<[PromiseResult]>,
+    /// Index of the next action to execute.
+    next_action: usize,
+    /// Receipts produced by the actions executed so far. Only committed once all of the actions
+    /// in the receipt succeed.
+    new_receipts: Vec<PendingReceipt>,
+    /// Return data of the most recently executed function call action.
+    return_data: ReturnData,
+    /// Number of the `new_receipts` produced before the most recently executed function call
+    /// action. A receipt index in its return data is relative to these.
+    return_receipts_offset: usize,
+    /// The receiver account as it was before any of the actions were applied.
+    snapshot: Option<Account>,
+}
+
+/// The result of executing a receipt.
+#[derive(serde::Serialize)]
+pub(crate) struct ReceiptOutcome {
+    pub(crate) receipt_id: CryptoHash,
+    pub(crate) predecessor_id: AccountId,
+    pub(crate) receiver_id: AccountId,
+    pub(crate) error: Option<String>,
+}
+
+#[wasm_bindgen]
+pub struct ReceiptScheduler {
+    world: World,
+    config: RuntimeConfig,
+    /// Context of the call that started it all. Block information, the signer and such are
+    /// copied over to the calls made for the receipts.
+    template: VMContext,
+    /// Hashes of the chain the call that started it all has been executed on. The action hashes
+    /// of the receipts are derived from the receipt ids and these.
+    chain: ChainHashes,
+    pending: VecDeque<PendingReceipt>,
+    /// Data received so far. `None` signifies the result of a failed execution.
+    data: BTreeMap<CryptoHash, Option<Vec<u8>>>,
+    in_flight: Option<InFlight>,
+    outcomes: Vec<ReceiptOutcome>,
+    /// Why the call the scheduler has been started with failed after it returned, see
+    /// [`Self::error`].
+    error: Option<String>,
+}
+
+#[wasm_bindgen]
+impl ReceiptScheduler {
+    /// Start scheduling the receipts produced by the (already executed) `logic`.
+    ///
+    /// If the execution succeeded, the resulting balance and storage usage of the account are
+    /// committed to the `world`. If the account then does not have enough balance to cover its
+    /// storage, no receipts are scheduled and [`Self::error`] reports why.
+    #[wasm_bindgen(constructor)]
+    pub fn new(world: &World, logic: &Logic, succeeded: bool) -> Self {
+        let ext = logic.external();
+        let mut scheduler = Self {
+            world: world.clone(),
+            config: ext.config().clone(),
+            template: logic.vm_logic().context.clone(),
+            chain: *ext.chain_hashes(),
+            pending: VecDeque::new(),
+            data: BTreeMap::new(),
+            in_flight: None,
+            outcomes: vec![],
+            error: None,
+        };
+        if succeeded {
+            scheduler.commit(logic);
+            let predecessor_id = scheduler.template.current_account_id.clone();
+            if let Err(error) = scheduler.check_storage_stake(&predecessor_id) {
+                scheduler.error = Some(error.to_string());
+                return scheduler;
+            }
+            let new_receipts = scheduler.collect_receipts(&ext, &predecessor_id);
+            scheduler.schedule(new_receipts);
+        }
+        scheduler
+    }
+
+    /// Time out the yielded receipts that would have timed out by the `block_height`, like
+    /// nearcore does once `yield_timeout_length_in_blocks` blocks have passed since the yield.
+    ///
+    /// Their callbacks are then executed by [`Self::next`], with a failed promise result, at the
+    /// `block_height`. Returns the number of receipts timed out.
+    pub fn time_out_yields(&mut self, block_height: &str) -> Result<usize> {
+        Ok(self.time_out_yields_at(Some(block_height.parse()?)))
+    }
+
+    /// Like [`Self::time_out_yields`], for all the yielded receipts regardless of when they time
+    /// out.
+    pub fn time_out_all_yields(&mut self) -> usize {
+        self.time_out_yields_at(None)
+    }
+
+    /// Why the call the scheduler has been started with failed even though the execution
+    /// succeeded, e.g. `LackBalanceForState`. The changes made by the call are not reverted, see
+    /// [`World::restore`].
+    pub fn error(&self) -> Option<String> {
+        self.error.clone()
+    }
+
+    /// Number of receipts that are still waiting for their data.
+    pub fn pending_count(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// The receipts executed so far along with the reason they failed, if they did.
+    pub fn outcomes(&self) -> Result<JsValue> {
+        self.outcomes
+            .serialize(&super::js_serializer())
+            .map_err(Into::into)
+    }
+
+    /// Obtain the next function call to execute, if there are any ready.
+    ///
+    /// Actions other than function calls are applied to the world directly. The caller must
+    /// report back via [`Self::complete`] or [`Self::abort`] before calling this method again.
+    pub fn next(&mut self) -> Option<ScheduledCall> {
+        loop {
+            if self.in_flight.is_none() {
+                let position = self.pending.iter().position(|p| {
+                    p.receipt
+                        .input_data_ids
+                        .iter()
+                        .all(|id| self.data.contains_key(id))
+                })?;
+                let pending = self.pending.remove(position)?;
+                let promise_results = pending
+                    .receipt
+                    .input_data_ids
+                    .iter()
+                    .map(|id| match self.data.remove(id).flatten() {
+                        Some(data) => PromiseResult::Successful(data),
+                        None => PromiseResult::Failed,
+                    })
+                    .collect::<Vec<_>>()
+                    .into();
+                let snapshot = self
+                    .world
+                    .with_account(&pending.receipt.receiver_id, |account| account.snapshot());
+                self.in_flight = Some(InFlight {
+                    pending,
+                    promise_results,
+                    next_action: 0,
+                    new_receipts: vec![],
+                    return_data: ReturnData::None,
+                    return_receipts_offset: 0,
+                    snapshot,
+                });
+            }
+            let in_flight = self
+                .in_flight
+                .as_mut()
+                .expect("in flight receipt was just set");
+            let receipt = &in_flight.pending.receipt;
+            let action_index = in_flight.next_action;
+            let Some(action) = receipt.actions.get(action_index) else {
+                self.finish(None);
+                continue;
+            };
+            in_flight.next_action += 1;
+            let Action::FunctionCall {
+                method_name,
+                args,
+                deposit,
+                gas,
+                ..
+            } = action
+            else {
+                let epoch_height = self.template.epoch_height;
+                match apply_action(&self.world, epoch_height, &in_flight.pending, action) {
+                    Ok(Some(refund)) => {
+                        let receipt_id = create_receipt_id_from_receipt_id(
+                            self.config.protocol_version(),
+                            &in_flight.pending.receipt_id,
+                            &self.chain,
+                            self.template.block_height,
+                            in_flight.new_receipts.len(),
+                        );
+                        in_flight.new_receipts.push(PendingReceipt {
+                            receipt_id,
+                            predecessor_id: "system".parse().expect("system is a valid account id"),
+                            receipt: refund,
+                        });
+                    }
+                    Ok(None) => {}
+                    Err(error) => self.finish(Some(error)),
+                }
+                continue;
+            };
+            let receiver_id = &receipt.receiver_id;
+            let account = self.world.with_account(receiver_id, |account| {
+                let balances = (account.amount, account.locked, account.storage_usage);
+                account.code.clone().map(|code| (code, balances))
+            });
+            let (code, (amount, locked, storage_usage)) = match account {
+                Some(Some(account)) => account,
+                Some(None) => {
+                    self.finish(Some(format!("{receiver_id} has no contract deployed")));
+                    continue;
+                }
+                None => {
+                    self.finish(Some(format!("account {receiver_id} does not exist")));
+                    continue;
+                }
+            };
+            let is_last_action = in_flight.next_action == receipt.actions.len();
+            let output_data_receivers = if is_last_action {
+                receipt
+                    .output_data_receivers
+                    .iter()
+                    .map(|r| r.receiver_id.clone())
+                    .collect()
+            } else {
+                vec![]
+            };
+            let context = VMContext {
+                current_account_id: receiver_id.clone(),
+                predecessor_account_id: in_flight.pending.predecessor_id.clone(),
+                input: args.clone(),
+                promise_results: in_flight.promise_results.clone(),
+                account_balance: amount,
+                account_locked_balance: locked,
+                storage_usage,
+                attached_deposit: *deposit,
+                prepaid_gas: *gas,
+                output_data_receivers,
+                ..self.template.clone()
+            };
+            let action_hash = create_action_hash(
+                self.config.protocol_version(),
+                &in_flight.pending.receipt_id,
+                &self.chain,
+                self.template.block_height,
+                action_index,
+            );
+            return Some(ScheduledCall {
+                world: self.world.clone(),
+                receiver_id: receiver_id.clone(),
+                method_name: method_name.clone(),
+                code,
+                context,
+                chain: ChainHashes {
+                    action_hash,
+                    ..self.chain
+                },
+                config: self.config.clone(),
+            });
+        }
+    }
+
+    /// Report the outcome of the call most recently returned by [`Self::next`]. `error` is the
+    /// reason the call failed, if it did.
+    pub fn complete(&mut self, logic: &Logic, error: Option<String>) {
+        let Some(in_flight) = self.in_flight.as_ref() else {
+            return;
+        };
+        if error.is_some() {
+            return self.finish(error);
+        }
+        self.commit(logic);
+        let predecessor_id = in_flight.pending.receipt.receiver_id.clone();
+        let new_receipts = self.collect_receipts(&logic.external(), &predecessor_id);
+        let return_data = logic.compute_outcome().return_data;
+        let in_flight = self.in_flight.as_mut().expect("checked above");
+        in_flight.return_receipts_offset = in_flight.new_receipts.len();
+        in_flight.new_receipts.extend(new_receipts);
+        in_flight.return_data = return_data;
+    }
+
+    /// Report that the call most recently returned by [`Self::next`] could not be executed at all
+    /// (e.g. because the contract failed to compile.)
+    pub fn abort(&mut self, error: String) {
+        if self.in_flight.is_some() {
+            self.finish(Some(error));
+        }
+    }
+}
+
+impl ReceiptScheduler {
+    pub(crate) fn receipt_outcomes(&self) -> &[ReceiptOutcome] {
+        &self.outcomes
+    }
+
+    /// Write the balance and storage usage resulting from a successful execution to the world.
+    fn commit(&self, logic: &Logic) {
+        let outcome = logic.compute_outcome();
+        let account_id = logic.vm_logic().context.current_account_id.clone();
+        self.world.with_account(&account_id, |account| {
+            account.amount = outcome.balance;
+            account.storage_usage = outcome.storage_usage;
+        });
+    }
+
+    /// Recompute the storage usage of the account from its contents once a receipt has been
+    /// applied to it, and check the account can still afford it, like nearcore does.
+    ///
+    /// Actions other than function calls (e.g. deploying a contract or adding a key) do not
+    /// update the storage usage as they are applied, so it is computed from scratch here.
+    fn check_storage_stake(
+        &self,
+        account_id: &AccountId,
+    ) -> std::result::Result<(), LackBalanceForState> {
+        let storage_usage_config = &self.config.fees().storage_usage_config;
+        self.world
+            .with_account(account_id, |account| {
+                account.storage_usage = account.compute_storage_usage(storage_usage_config);
+                check_storage_stake(account_id, account, &self.config)
+            })
+            .unwrap_or(Ok(()))
+    }
+
+    /// Queue the receipts produced by a successful execution.
+    ///
+    /// Yielded receipts are kept in the world instead, so that later calls can resume them, and
+    /// the ones that have just been resumed are taken back from the world.
+    fn schedule(&mut self, new_receipts: Vec<PendingReceipt>) {
+        let expires_at = self.template.block_height
+            + self
+                .config
+                .wasm_config()
+                .limit_config
+                .yield_timeout_length_in_blocks;
+        for pending in new_receipts {
+            match pending.receipt.input_data_ids.first() {
+                Some(&data_id) if pending.receipt.is_promise_yield => {
+                    let yielded = YieldedReceipt {
+                        data_id,
+                        expires_at,
+                        pending,
+                    };
+                    self.world.insert_yield(yielded);
+                }
+                _ => self.pending.push_back(pending),
+            }
+        }
+        let resumed = self
+            .world
+            .take_yields(|yielded| self.data.contains_key(&yielded.data_id));
+        self.pending
+            .extend(resumed.into_iter().map(|yielded| yielded.pending));
+    }
+
+    /// See [`Self::time_out_yields`]. `None` times out all of the yielded receipts, at the height
+    /// the last one of them times out.
+    fn time_out_yields_at(&mut self, block_height: Option<BlockHeight>) -> usize {
+        let expired = self.world.take_yields(|yielded| {
+            block_height.is_none_or(|block_height| yielded.expires_at <= block_height)
+        });
+        let Some(last_expiry) = expired.iter().map(|yielded| yielded.expires_at).max() else {
+            return 0;
+        };
+        let block_height = block_height.unwrap_or(last_expiry);
+        self.template.block_height = self.template.block_height.max(block_height);
+        let count = expired.len();
+        for yielded in expired {
+            self.data.insert(yielded.data_id, None);
+            self.pending.push_back(yielded.pending);
+        }
+        count
+    }
+
+    /// Take the receipts and data produced by an execution.
+    fn collect_receipts(
+        &mut self,
+        ext: &DebugExternal,
+        predecessor_id: &AccountId,
+    ) -> Vec<PendingReceipt> {
+        for data_receipt in ext.data_receipts() {
+            self.data
+                .insert(data_receipt.data_id, data_receipt.data.clone());
+        }
+        ext.receipts()
+            .iter()
+            .enumerate()
+            .map(|(index, receipt)| PendingReceipt {
+                receipt_id: ext.create_hash(&ext.chain_hashes().action_hash, index as u64),
+                predecessor_id: predecessor_id.clone(),
+                receipt: receipt.clone(),
+            })
+            .collect()
+    }
+
+    /// Finish executing the in-flight receipt and deliver its result to the receivers.
+    ///
+    /// If the receipt failed with an `error`, the changes made to the receiver account are
+    /// reverted and the attached deposits are refunded to the predecessor.
+    fn finish(&mut self, mut error: Option<String>) {
+        let Some(in_flight) = self.in_flight.take() else {
+            return;
+        };
+        if error.is_none() {
+            let receiver_id = &in_flight.pending.receipt.receiver_id;
+            error = self
+                .check_storage_stake(receiver_id)
+                .err()
+                .map(|e| e.to_string());
+        }
+        let PendingReceipt {
+            receipt_id,
+            predecessor_id,
+            receipt,
+        } = in_flight.pending;
+        self.outcomes.push(ReceiptOutcome {
+            receipt_id,
+            predecessor_id: predecessor_id.clone(),
+            receiver_id: receipt.receiver_id.clone(),
+            error: error.clone(),
+        });
+        if error.is_some() {
+            self.world
+                .restore_account(&receipt.receiver_id, in_flight.snapshot);
+            let refund = receipt
+                .actions
+                .iter()
+                .map(|action| match action {
+                    Action::FunctionCall { deposit, .. } | Action::Transfer { deposit } => *deposit,
+                    _ => 0,
+                })
+                .sum::<Balance>();
+            self.world.with_account(&predecessor_id, |account| {
+                account.amount += refund;
+            });
+            return self.deliver(receipt.output_data_receivers, None);
+        }
+        let receivers = receipt.output_data_receivers;
+        let mut new_receipts = in_flight.new_receipts;
+        match in_flight.return_data {
+            ReturnData::Value(data) => self.deliver(receivers, Some(data)),
+            ReturnData::None => self.deliver(receivers, Some(vec![])),
+            ReturnData::ReceiptIndex(index) => {
+                let index = in_flight.return_receipts_offset + index as usize;
+                match new_receipts.get_mut(index) {
+                    Some(receipt) => receipt.receipt.output_data_receivers.extend(receivers),
+                    None => self.deliver(receivers, None),
+                }
+            }
+        }
+        self.schedule(new_receipts);
+    }
+
+    fn deliver(&mut self, receivers: Vec<DataReceiver>, data: Option<Vec<u8>>) {
+        for receiver in receivers {
+            self.data.insert(receiver.data_id, data.clone());
+        }
+    }
+}
+
+/// Apply an action that does not require executing any code to the receiver account in the epoch
+/// `epoch_height`. Returns the receipt refunding the balance of a deleted account.
+fn apply_action(
+    world: &World,
+    epoch_height: EpochHeight,
+    pending: &PendingReceipt,
+    action: &Action,
+) -> std::result::Result<Option<Receipt>, String> {
+    let receiver_id = &pending.receipt.receiver_id;
+    let update = |f: &mut dyn FnMut(&mut Account) -> std::result::Result<(), String>| {
+        world
+            .with_account(receiver_id, f)
+            .unwrap_or_else(|| Err(format!("account {receiver_id} does not exist")))
+    };
+    let mut refund = None;
+    match action {
+        Action::CreateAccount => {
+            if !world.insert_account(receiver_id, 0) {
+                return Err(format!("account {receiver_id} already exists"));
+            }
+            Ok(())
+        }
+        Action::DeployContract { code, .. } => update(&mut |account| {
+            account.code = Some(code.as_slice().into());
+            Ok(())
+        }),
+        Action::FunctionCall { .. } => unreachable!("function calls are executed by the embedder"),
+        Action::Transfer { deposit } => update(&mut |account| {
+            account.amount = account
+                .amount
+                .checked_add(*deposit)
+                .ok_or_else(|| format!("the balance of {receiver_id} would overflow"))?;
+            Ok(())
+        }),
+        // The stake becomes that of the validator in the next epoch. Raising the stake locks the
+        // tokens right away, whereas the tokens of a lowered stake remain locked until then.
+        Action::Stake { stake, .. } => {
+            let mut lowered = false;
+            update(&mut |account| {
+                let Some(increment) = stake.checked_sub(account.locked) else {
+                    lowered = true;
+                    return Ok(());
+                };
+                account.amount = account.amount.checked_sub(increment).ok_or_else(|| {
+                    format!("{receiver_id} does not have enough balance to stake")
+                })?;
+                account.locked = *stake;
+                Ok(())
+            })?;
+            let unstake = lowered.then_some((epoch_height + 1, *stake));
+            world.schedule_unstake(receiver_id, unstake);
+            world.update_validator_stake(epoch_height + 1, receiver_id.clone(), *stake);
+            Ok(())
+        }
+        Action::AddKeyWithFullAccess { public_key, nonce } => update(&mut |account| {
+            let key = AccessKey {
+                nonce: *nonce,
+                permission: AccessKeyPermission::FullAccess,
+            };
+            add_key(account, public_key, key)
+        }),
+        Action::AddKeyWithFunctionCall {
+            public_key,
+            nonce,
+            allowance,
+            receiver_id,
+            method_names,
+        } => update(&mut |account| {
+            let permission = AccessKeyPermission::FunctionCall {
+                allowance: *allowance,
+                receiver_id: receiver_id.clone(),
+                method_names: method_names.clone(),
+            };
+            let key = AccessKey {
+                nonce: *nonce,
+                permission,
+            };
+            add_key(account, public_key, key)
+        }),
+        Action::DeleteKey { public_key } => update(&mut |account| match account
+            .access_keys
+            .remove(public_key)
+        {
+            Some(_) => Ok(()),
+            None => Err(format!("access key {public_key} does not exist")),
+        }),
+        Action::DeleteAccount { beneficiary_id } => {
+            let account = world
+                .remove_account(receiver_id)
+                .ok_or_else(|| format!("account {receiver_id} does not exist"))?;
+            // Like nearcore, the remaining balance is sent to the beneficiary in a refund receipt,
+            // which fails (and the balance is lost) if the beneficiary does not exist.
+            if account.amount > 0 {
+                let mut receipt = Receipt::new(beneficiary_id.clone());
+                receipt.actions.push(Action::Transfer {
+                    deposit: account.amount,
+                });
+                refund = Some(receipt);
+            }
+            Ok(())
+        }
+    }?;
+    Ok(refund)
+}
+
+fn add_key(
+    account: &mut Account,
+    public_key: &near_crypto::PublicKey,
+    key: AccessKey,
+) -> std::result::Result<(), String> {
+    if account.access_keys.contains_key(public_key) {
+        return Err(format!("access key {public_key} already exists"));
+    }
+    account.access_keys.insert(public_key.clone(), key);
+    Ok(())
+}
+
+/// A function call action of a receipt that is ready to be executed.
+#[wasm_bindgen]
+pub struct ScheduledCall {
+    world: World,
+    receiver_id: AccountId,
+    method_name: String,
+    code: Arc<[u8]>,
+    context: VMContext,
+    chain: ChainHashes,
+    config: RuntimeConfig,
+}
+
+#[wasm_bindgen]
+impl ScheduledCall {
+    #[wasm_bindgen(getter)]
+    pub fn receiver_id(&self) -> String {
+        self.receiver_id.to_string()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn method_name(&self) -> String {
+        self.method_name.clone()
+    }
+
+    /// The contract code of the receiver.
+    pub fn code(&self) -> Vec<u8> {
+        self.code.to_vec()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn code_hash(&self) -> String {
+        CryptoHash::hash_bytes(&self.code).to_string()
+    }
+
+    /// The context to execute this call with.
+    pub fn context(&self) -> Context {
+        Context(self.context.clone())
+    }
+
+    /// Construct the external for executing this call on behalf of the receiver.
+    pub fn external(&self) -> Result<DebugExternal> {
+        self.debug_external().map_err(|e| JsError::new(&e))
+    }
+}
+
+impl ScheduledCall {
+    pub(crate) fn code_bytes(&self) -> &[u8] {
+        &self.code
+    }
+
+    pub(crate) fn debug_external(&self) -> std::result::Result<DebugExternal, String> {
+        DebugExternal::with_chain_hashes(
+            &self.world,
+            &self.context(),
+            self.config.clone(),
+            self.chain,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::near_vm_runner::logic::{MemSlice, MemoryLike, VMLogic};
+    use near_primitives_core::types::Gas;
+    use near_primitives_core::version::PROTOCOL_VERSION;
+    use std::borrow::Cow;
+
+    /// The strings the test contracts pass to the host functions, 16 bytes apart in their memory.
+    const STRINGS: [&str; 10] = [
+        "bob.near",
+        "alice.near",
+        "carol.near",
+        "middle",
+        "callback",
+        "leaf",
+        "spawn",
+        "other",
+        "result",
+        "fail",
+    ];
+    /// Where the zero deposit and a deposit of [`DEPOSIT`] yoctoNEAR are kept in the memory.
+    const ZERO: u64 = 1024;
+    const DEPOSIT_PTR: u64 = 1040;
+    const DEPOSIT: Balance = 1000;
+    const BALANCE: Balance = 100_000_000_000_000_000_000_000_000;
+    /// Gas attached to the calls made by the top-level call and by the receipts respectively.
+    const GAS: Gas = 80_000_000_000_000;
+    const INNER_GAS: Gas = 20_000_000_000_000;
+
+    /// Memory of a contract that is not actually there: the tests call the host functions of the
+    /// [`VMLogic`] directly.
+    struct TestMemory(Vec<u8>);
+
+    impl TestMemory {
+        fn new() -> Self {
+            let mut memory = vec![0; 2048];
+            for (index, string) in STRINGS.iter().enumerate() {
+                memory[index * 16..][..string.len()].copy_from_slice(string.as_bytes());
+            }
+            memory[DEPOSIT_PTR as usize..][..16].copy_from_slice(&DEPOSIT.to_le_bytes());
+            Self(memory)
+        }
+    }
+
+    impl MemoryLike for TestMemory {
+        fn fits_memory(&self, slice: MemSlice) -> std::result::Result<(), ()> {
+            (slice.end::<usize>()? <= self.0.len())
+                .then_some(())
+                .ok_or(())
+        }
+
+        fn view_memory(&self, slice: MemSlice) -> std::result::Result<Cow<[u8]>, ()> {
+            self.0
+                .get(slice.range::<usize>()?)
+                .map(Cow::Borrowed)
+                .ok_or(())
+        }
+
+        fn read_memory(&self, offset: u64, buffer: &mut [u8]) -> std::result::Result<(), ()> {
+            let slice = MemSlice {
+                ptr: offset,
+                len: buffer.len() as u64,
+            };
+            buffer.copy_from_slice(&self.view_memory(slice)?);
+            Ok(())
+        }
+
+        fn write_memory(&mut self, offset: u64, buffer: &[u8]) -> std::result::Result<(), ()> {
+            let slice = MemSlice {
+                ptr: offset,
+                len: buffer.len() as u64,
+            };
+            let range = slice.range::<usize>()?;
+            self.0.get_mut(range).ok_or(())?.copy_from_slice(buffer);
+            Ok(())
+        }
+    }
+
+    /// Length and pointer of one of the [`STRINGS`].
+    fn string(value: &str) -> (u64, u64) {
+        let index = STRINGS.iter().position(|s| *s == value).unwrap();
+        (value.len() as u64, index as u64 * 16)
+    }
+
+    fn logic(context: Context, ext: DebugExternal) -> Logic {
+        Logic::with_memory(context, Box::new(TestMemory::new()), ext)
+    }
+
+    fn promise_create(logic: &mut VMLogic, account_id: &str, method_name: &str, gas: Gas) -> u64 {
+        let (account_len, account_ptr) = string(account_id);
+        let (method_len, method_ptr) = string(method_name);
+        logic
+            .promise_create(
+                account_len,
+                account_ptr,
+                method_len,
+                method_ptr,
+                0,
+                0,
+                ZERO,
+                gas,
+            )
+            .unwrap()
+    }
+
+    fn promise_then(logic: &mut VMLogic, promise: u64, account_id: &str, method_name: &str) {
+        let (account_len, account_ptr) = string(account_id);
+        let (method_len, method_ptr) = string(method_name);
+        logic
+            .promise_then(
+                promise,
+                account_len,
+                account_ptr,
+                method_len,
+                method_ptr,
+                0,
+                0,
+                ZERO,
+                GAS,
+            )
+            .unwrap();
+    }
+
+    fn function_call(logic: &mut VMLogic, promise: u64, method_name: &str) {
+        let (method_len, method_ptr) = string(method_name);
+        logic
+            .promise_batch_action_function_call(promise, method_len, method_ptr, 0, 0, ZERO, GAS)
+            .unwrap();
+    }
+
+    fn value_return(logic: &mut VMLogic, value: &str) {
+        let (len, ptr) = string(value);
+        logic.value_return(len, ptr).unwrap();
+    }
+
+    /// The methods of the test contracts. Returns the error the method fails with, if it does.
+    fn execute(method_name: &str, logic: &mut VMLogic) -> Option<String> {
+        match method_name {
+            // Returns the result of `bob.leaf`.
+            "middle" => {
+                let promise = promise_create(logic, "bob.near", "leaf", INNER_GAS);
+                logic.promise_return(promise).unwrap();
+            }
+            "leaf" => value_return(logic, "leaf"),
+            // Calls `bob.other` without returning its result.
+            "spawn" => {
+                promise_create(logic, "bob.near", "other", INNER_GAS);
+            }
+            "other" => value_return(logic, "other"),
+            "callback" => {}
+            // Writes to the storage before failing.
+            "fail" => {
+                let (key_len, key_ptr) = string("result");
+                let (value_len, value_ptr) = string("fail");
+                logic
+                    .storage_write(key_len, key_ptr, value_len, value_ptr, 0)
+                    .unwrap();
+                return Some("the method has failed".to_string());
+            }
+            _ => panic!("unexpected method {method_name}"),
+        }
+        None
+    }
+
+    fn alice() -> AccountId {
+        "alice.near".parse().unwrap()
+    }
+
+    fn bob() -> AccountId {
+        "bob.near".parse().unwrap()
+    }
+
+    /// `alice.near` and `bob.near`, both with a contract.
+    fn world() -> World {
+        let world = World::new();
+        for account_id in [alice(), bob()] {
+            world.insert_account(&account_id, BALANCE);
+            world.with_account(&account_id, |account| {
+                account.code = Some(Vec::new().into())
+            });
+        }
+        world
+    }
+
+    /// Execute a call of `alice.near` doing what the `body` does and schedule the receipts it
+    /// produces.
+    fn call(world: &World, body: impl FnOnce(&mut VMLogic)) -> ReceiptScheduler {
+        let context = Context::new();
+        let config = RuntimeConfig::new(PROTOCOL_VERSION);
+        let ext = DebugExternal::with_chain_hashes(world, &context, config, ChainHashes::default())
+            .unwrap();
+        let logic = logic(context, ext);
+        body(&mut logic.vm_logic_mut());
+        ReceiptScheduler::new(world, &logic, true)
+    }
+
+    /// Execute the receipts until there are none ready and return the promise results the
+    /// callbacks have received, with `None` standing for a failed promise.
+    fn execute_receipts(scheduler: &mut ReceiptScheduler) -> Vec<Option<Vec<u8>>> {
+        let mut results = vec![];
+        while let Some(call) = scheduler.next() {
+            if call.method_name == "callback" {
+                results.extend(
+                    call.context
+                        .promise_results
+                        .iter()
+                        .map(|result| match result {
+                            PromiseResult::Successful(data) => Some(data.clone()),
+                            PromiseResult::Failed | PromiseResult::NotReady => None,
+                        }),
+                );
+            }
+            let mut logic = logic(call.context(), call.debug_external().unwrap());
+            let error = execute(&call.method_name, &mut logic.vm_logic_mut());
+            scheduler.complete(&logic, error);
+        }
+        assert_eq!(scheduler.pending_count(), 0);
+        results
+    }
+
+    #[test]
+    fn test_nested_promise_result_reaches_callback() {
+        let world = world();
+        let mut scheduler = call(&world, |logic| {
+            let promise = promise_create(logic, "bob.near", "middle", GAS);
+            promise_then(logic, promise, "alice.near", "callback");
+        });
+        let results = execute_receipts(&mut scheduler);
+        assert_eq!(results, [Some(b"leaf".to_vec())]);
+    }
+
+    #[test]
+    fn test_receipt_index_relative_to_its_function_call() {
+        let world = world();
+        let mut scheduler = call(&world, |logic| {
+            let (account_len, account_ptr) = string("bob.near");
+            let promise = logic
+                .promise_batch_create(account_len, account_ptr)
+                .unwrap();
+            function_call(logic, promise, "spawn");
+            function_call(logic, promise, "middle");
+            promise_then(logic, promise, "alice.near", "callback");
+        });
+        // `middle` returns the first receipt it creates, not the one `spawn` has created before.
+        let results = execute_receipts(&mut scheduler);
+        assert_eq!(results, [Some(b"leaf".to_vec())]);
+    }
+
+    #[test]
+    fn test_failed_receipt_is_reverted_and_refunded() {
+        let world = world();
+        let mut scheduler = call(&world, |logic| {
+            let (account_len, account_ptr) = string("bob.near");
+            let (method_len, method_ptr) = string("fail");
+            logic
+                .promise_create(
+                    account_len,
+                    account_ptr,
+                    method_len,
+                    method_ptr,
+                    0,
+                    0,
+                    DEPOSIT_PTR,
+                    GAS,
+                )
+                .unwrap();
+        });
+        let amount =
+            |account_id: AccountId| world.with_account(&account_id, |account| account.amount);
+        let alice_after_call = amount(alice()).unwrap();
+        execute_receipts(&mut scheduler);
+        assert!(scheduler.outcomes[0].error.is_some());
+        let stored = world.with_account(&bob(), |account| account.storage.get(b"result"));
+        assert_eq!(stored, Some(None));
+        assert_eq!(amount(bob()), Some(BALANCE));
+        assert_eq!(amount(alice()), Some(alice_after_call + DEPOSIT));
+    }
+
+    /// Execute a call of `alice.near` deleting the account in favour of the `beneficiary` along
+    /// with the receipts it produces. Returns the balance of the account as it was deleted.
+    fn delete_alice(world: &World, beneficiary: &str) -> (ReceiptScheduler, Balance) {
+        let mut scheduler = call(world, |logic| {
+            let (account_len, account_ptr) = string("alice.near");
+            let promise = logic
+                .promise_batch_create(account_len, account_ptr)
+                .unwrap();
+            let (beneficiary_len, beneficiary_ptr) = string(beneficiary);
+            logic
+                .promise_batch_action_delete_account(promise, beneficiary_len, beneficiary_ptr)
+                .unwrap();
+        });
+        let balance = world
+            .with_account(&alice(), |account| account.amount)
+            .unwrap();
+        execute_receipts(&mut scheduler);
+        assert!(world.with_account(&alice(), |_| ()).is_none());
+        (scheduler, balance)
+    }
+
+    /// The predecessor and receiver of the receipts executed so far, and whether they failed.
+    fn outcomes(scheduler: &ReceiptScheduler) -> Vec<(&str, &str, bool)> {
+        scheduler
+            .outcomes
+            .iter()
+            .map(|outcome| {
+                let predecessor_id = outcome.predecessor_id.as_str();
+                (
+                    predecessor_id,
+                    outcome.receiver_id.as_str(),
+                    outcome.error.is_some(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_deleted_account_balance_is_refunded_to_beneficiary() {
+        let world = world();
+        let (scheduler, balance) = delete_alice(&world, "bob.near");
+        assert_eq!(
+            outcomes(&scheduler),
+            [
+                ("alice.near", "alice.near", false),
+                ("system", "bob.near", false)
+            ]
+        );
+        let bob = world.with_account(&bob(), |account| account.amount);
+        assert_eq!(bob, Some(BALANCE + balance));
+    }
+
+    #[test]
+    fn test_refund_to_missing_beneficiary_fails() {
+        let world = world();
+        let (scheduler, _) = delete_alice(&world, "carol.near");
+        // Like in nearcore, the refund fails and the balance is lost.
+        assert_eq!(
+            outcomes(&scheduler),
+            [
+                ("alice.near", "alice.near", false),
+                ("system", "carol.near", true)
+            ]
+        );
+        assert_eq!(
+            scheduler.outcomes[1].error.as_deref(),
+            Some("account carol.near does not exist")
+        );
+        assert!(world
+            .with_account(&"carol.near".parse().unwrap(), |_| ())
+            .is_none());
+        assert_eq!(world.accounts(), ["bob.near"]);
+        let bob = world.with_account(&bob(), |account| account.amount);
+        assert_eq!(bob, Some(BALANCE));
+    }
+}
@@ -0,0 +1,51 @@
+//! The storage staking rules nearcore enforces once it has applied a receipt.
+use super::world::Account;
+use super::RuntimeConfig;
+use near_primitives_core::types::{AccountId, Balance, StorageUsage};
+use near_primitives_core::version::ProtocolFeature;
+
+/// Accounts using at most this much storage do not need to hold any balance to cover it, since
+/// the zero balance accounts protocol feature. Mirrors `ZERO_BALANCE_ACCOUNT_STORAGE_LIMIT` in
+/// nearcore.
+const ZERO_BALANCE_ACCOUNT_STORAGE_LIMIT: StorageUsage = 770;
+
+/// The account would not have enough balance to cover its storage usage, so nearcore fails the
+/// receipt with the `LackBalanceForState` action error.
+#[derive(Clone, Debug)]
+pub(crate) struct LackBalanceForState {
+    account_id: AccountId,
+    /// The balance missing to cover the storage.
+    amount: Balance,
+}
+
+impl std::fmt::Display for LackBalanceForState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "LackBalanceForState: the account {} wouldn't have enough balance to cover storage, \
+             required to have {} yoctoNEAR more",
+            self.account_id, self.amount
+        )
+    }
+}
+
+/// Check that the balance of the account (locked balance included) covers the storage it uses.
+pub(crate) fn check_storage_stake(
+    account_id: &AccountId,
+    account: &Account,
+    config: &RuntimeConfig,
+) -> Result<(), LackBalanceForState> {
+    let storage_amount_per_byte = config.fees().storage_usage_config.storage_amount_per_byte;
+    let required = Balance::from(account.storage_usage).saturating_mul(storage_amount_per_byte);
+    let available = account.amount.saturating_add(account.locked);
+    let zero_balance_account = ProtocolFeature::ZeroBalanceAccount
+        .enabled(config.protocol_version())
+        && account.storage_usage <= ZERO_BALANCE_ACCOUNT_STORAGE_LIMIT;
+    if available >= required || zero_balance_account {
+        return Ok(());
+    }
+    Err(LackBalanceForState {
+        account_id: account_id.clone(),
+        amount: required - available,
+    })
+}
@@ -0,0 +1,71 @@
+//! Changes made to the contract storage by a single execution.
+use super::Store;
+use serde_with::serde_as;
+use std::collections::BTreeMap;
+
+/// The values the storage entries changed during an execution had before they were first changed.
+#[derive(Default)]
+pub(crate) struct StorageChanges {
+    /// `None` signifies the entry did not exist.
+    original: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+impl StorageChanges {
+    /// Remember the value of the `key` before it is changed, unless it has been changed already.
+    pub(crate) fn touch(&mut self, store: &Store, key: &[u8]) {
+        if !self.original.contains_key(key) {
+            self.original.insert(key.to_vec(), store.get(key));
+        }
+    }
+
+    /// Compare the remembered values with the current contents of the `store`.
+    ///
+    /// Entries that have been changed and then changed back are not part of the diff.
+    pub(crate) fn diff(&self, store: &Store) -> StorageDiff {
+        let mut diff = StorageDiff::default();
+        for (key, original) in &self.original {
+            let key = key.clone();
+            match (original.clone(), store.get(&key)) {
+                (None, Some(value)) => diff.added.push(Entry { key, value }),
+                (Some(value), None) => diff.removed.push(Entry { key, value }),
+                (Some(old_value), Some(new_value)) if old_value != new_value => {
+                    diff.modified.push(Modification {
+                        key,
+                        old_value,
+                        new_value,
+                    })
+                }
+                _ => {}
+            }
+        }
+        diff
+    }
+}
+
+#[serde_as]
+#[derive(serde::Serialize)]
+pub(crate) struct Entry {
+    #[serde_as(as = "serde_with::base64::Base64")]
+    key: Vec<u8>,
+    #[serde_as(as = "serde_with::base64::Base64")]
+    value: Vec<u8>,
+}
+
+#[serde_as]
+#[derive(serde::Serialize)]
+pub(crate) struct Modification {
+    #[serde_as(as = "serde_with::base64::Base64")]
+    key: Vec<u8>,
+    #[serde_as(as = "serde_with::base64::Base64")]
+    old_value: Vec<u8>,
+    #[serde_as(as = "serde_with::base64::Base64")]
+    new_value: Vec<u8>,
+}
+
+/// Storage entries added, modified and removed by an execution, ordered by key.
+#[derive(Default, serde::Serialize)]
+pub(crate) struct StorageDiff {
+    pub(crate) added: Vec<Entry>,
+    pub(crate) modified: Vec<Modification>,
+    pub(crate) removed: Vec<Entry>,
+}
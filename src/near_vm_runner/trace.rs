@@ -0,0 +1,285 @@
+//! Recording of the host functions called by a contract.
+//!
+//! The arguments of the calls are decoded from the conventions the host functions follow: a
+//! `foo_len` and `foo_ptr` pair refers to data in the guest memory (or a register, if the length
+//! is `u64::MAX`), a lone `*_ptr` to a balance and a `*register_id` to a register the host function
+//! reads from or writes to.
+use super::logic::{VMLogic, VMLogicError};
+use near_parameters::ExtCosts;
+use near_primitives_core::types::{Balance, Gas};
+use serde_with::{serde_as, DisplayFromStr};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::rc::Rc;
+
+/// Names of the pointer arguments that point to a balance.
+const BALANCE_POINTERS: &[&str] = &["balance_ptr", "amount_ptr", "stake_ptr", "allowance_ptr"];
+
+#[serde_as]
+#[derive(Clone, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ArgValue {
+    Integer(u64),
+    Balance(#[serde_as(as = "DisplayFromStr")] Balance),
+    Data(#[serde_as(as = "serde_with::base64::Base64")] Vec<u8>),
+    Register {
+        register_id: u64,
+        #[serde_as(as = "Option<serde_with::base64::Base64>")]
+        data: Option<Vec<u8>>,
+    },
+}
+
+#[derive(Clone, serde::Serialize)]
+pub(crate) struct Arg {
+    name: String,
+    value: ArgValue,
+}
+
+/// A host function called by the contract.
+#[derive(Clone, serde::Serialize)]
+pub(crate) struct HostCall {
+    name: &'static str,
+    args: Vec<Arg>,
+    /// The value returned, if the host function returns one and succeeded.
+    result: Option<u64>,
+    error: Option<String>,
+    burnt_gas_before: Gas,
+    burnt_gas_after: Gas,
+    /// Gas charged for each of the `ExtCosts` during the call.
+    ext_costs: BTreeMap<String, Gas>,
+}
+
+/// Shared record of the host calls, if tracing is enabled.
+#[derive(Clone, Default)]
+pub(crate) struct Tracer(Rc<RefCell<Option<Vec<HostCall>>>>);
+
+impl Tracer {
+    pub(crate) fn enable(&self) {
+        self.0.borrow_mut().get_or_insert_with(Vec::new);
+    }
+
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.0.borrow().is_some()
+    }
+
+    pub(crate) fn calls(&self) -> Vec<HostCall> {
+        self.0.borrow().clone().unwrap_or_default()
+    }
+
+    fn record(&self, call: HostCall) {
+        if let Some(calls) = &mut *self.0.borrow_mut() {
+            calls.push(call);
+        }
+    }
+}
+
+/// Raw value of a host function argument.
+pub(crate) trait RawArg {
+    fn raw(&self) -> u64;
+}
+
+impl RawArg for u64 {
+    fn raw(&self) -> u64 {
+        *self
+    }
+}
+
+impl RawArg for u32 {
+    fn raw(&self) -> u64 {
+        u64::from(*self)
+    }
+}
+
+/// The value returned by a host function, as recorded in the trace.
+pub(crate) trait ReturnValue {
+    fn traced(&self) -> Option<u64>;
+}
+
+impl ReturnValue for () {
+    fn traced(&self) -> Option<u64> {
+        None
+    }
+}
+
+impl ReturnValue for u64 {
+    fn traced(&self) -> Option<u64> {
+        Some(*self)
+    }
+}
+
+impl ReturnValue for u32 {
+    fn traced(&self) -> Option<u64> {
+        Some(u64::from(*self))
+    }
+}
+
+/// A host call that is in progress, holding on to the state from before the call.
+pub(crate) struct PendingCall {
+    tracer: Tracer,
+    name: &'static str,
+    raw_args: Vec<(&'static str, u64)>,
+    /// Data the arguments point to, read before the host function had a chance to change it.
+    data: BTreeMap<&'static str, Vec<u8>>,
+    burnt_gas: Gas,
+    ext_profile: enum_map::EnumMap<ExtCosts, Gas>,
+}
+
+impl PendingCall {
+    /// Start recording a call to `name`, if there is a `tracer` and it is enabled.
+    pub(crate) fn begin(
+        tracer: Option<&Tracer>,
+        logic: &mut VMLogic,
+        name: &'static str,
+        raw_args: &[(&'static str, u64)],
+    ) -> Option<Self> {
+        let tracer = tracer.filter(|tracer| tracer.is_enabled())?;
+        let mut data = BTreeMap::new();
+        for &(arg, len) in raw_args {
+            let Some(prefix) = arg.strip_suffix("len") else {
+                continue;
+            };
+            let ptr_name = format!("{prefix}ptr");
+            let Some(&(_, ptr)) = raw_args.iter().find(|(name, _)| *name == ptr_name) else {
+                continue;
+            };
+            let value = if len == u64::MAX {
+                logic.peek_register(ptr).map(<[u8]>::to_vec)
+            } else {
+                logic.peek_memory(ptr, len)
+            };
+            if let Some(value) = value {
+                data.insert(arg, value);
+            }
+        }
+        let gas_counter = logic.gas_counter();
+        Some(Self {
+            tracer: tracer.clone(),
+            name,
+            raw_args: raw_args.to_vec(),
+            data,
+            burnt_gas: gas_counter.burnt_gas(),
+            ext_profile: gas_counter.profile_data().wasm_ext_profile,
+        })
+    }
+
+    /// Record the call with the `result` it has returned.
+    pub(crate) fn end(
+        self,
+        logic: &mut VMLogic,
+        result: Result<Option<u64>, &VMLogicError>,
+    ) -> HostCall {
+        let mut args = vec![];
+        for &(name, raw) in &self.raw_args {
+            if let Some(prefix) = name.strip_suffix("ptr") {
+                if self.data.contains_key(format!("{prefix}len").as_str()) {
+                    // Already decoded along with the length.
+                    continue;
+                }
+            }
+            if let Some(data) = self.data.get(name) {
+                let name = match name.strip_suffix("_len") {
+                    Some(prefix) => prefix.to_string(),
+                    None => "data".to_string(),
+                };
+                let value = ArgValue::Data(data.clone());
+                args.push(Arg { name, value });
+                continue;
+            }
+            let value = if name.ends_with("register_id") {
+                ArgValue::Register {
+                    register_id: raw,
+                    data: logic.peek_register(raw).map(<[u8]>::to_vec),
+                }
+            } else if BALANCE_POINTERS.contains(&name) {
+                match logic.peek_memory(raw, size_of::<Balance>() as u64) {
+                    Some(bytes) => ArgValue::Balance(Balance::from_le_bytes(
+                        bytes
+                            .try_into()
+                            .expect("read exactly the size of a balance"),
+                    )),
+                    None => ArgValue::Integer(raw),
+                }
+            } else {
+                ArgValue::Integer(raw)
+            };
+            let name = name.to_string();
+            args.push(Arg { name, value });
+        }
+        let gas_counter = logic.gas_counter();
+        let ext_profile = gas_counter.profile_data().wasm_ext_profile;
+        let ext_costs = ext_profile
+            .iter()
+            .filter_map(|(cost, &after)| {
+                let charged = after.saturating_sub(self.ext_profile[cost]);
+                (charged > 0).then(|| (cost.to_string(), charged))
+            })
+            .collect();
+        let call = HostCall {
+            name: self.name,
+            args,
+            result: result.as_ref().ok().copied().flatten(),
+            error: result.err().map(ToString::to_string),
+            burnt_gas_before: self.burnt_gas,
+            burnt_gas_after: gas_counter.burnt_gas(),
+            ext_costs,
+        };
+        self.tracer.record(call.clone());
+        call
+    }
+}
+
+impl fmt::Display for ArgValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fn data(f: &mut fmt::Formatter<'_>, data: &[u8]) -> fmt::Result {
+            match std::str::from_utf8(data) {
+                Ok(text) => write!(f, "{text:?}"),
+                Err(_) => write!(f, "0x{}", hex(data)),
+            }
+        }
+        match self {
+            ArgValue::Integer(value) => write!(f, "{value}"),
+            ArgValue::Balance(balance) => write!(f, "{balance}yN"),
+            ArgValue::Data(bytes) => data(f, bytes),
+            ArgValue::Register {
+                register_id,
+                data: Some(bytes),
+            } => {
+                write!(f, "#{register_id}=")?;
+                data(f, bytes)
+            }
+            ArgValue::Register {
+                register_id,
+                data: None,
+            } => write!(f, "#{register_id}"),
+        }
+    }
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// A one-line summary, e.g. `storage_read(key: "counter", register: #0="1") -> 1`.
+impl fmt::Display for HostCall {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}(", self.name)?;
+        for (index, arg) in self.args.iter().enumerate() {
+            if index > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}: {}", arg.name, arg.value)?;
+        }
+        write!(f, ")")?;
+        match (&self.result, &self.error) {
+            (_, Some(error)) => write!(f, " -> error: {error}")?,
+            (Some(result), None) => write!(f, " -> {result}")?,
+            (None, None) => {}
+        }
+        write!(
+            f,
+            " [{} gas]",
+            self.burnt_gas_after.saturating_sub(self.burnt_gas_before)
+        )
+    }
+}
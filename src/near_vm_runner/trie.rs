@@ -0,0 +1,452 @@
+//! A model of the Merkle-Patricia trie nearcore keeps the contract storage in, so that accessing
+//! the storage charges for the trie nodes touched and records a storage proof the way nearcore
+//! does.
+//!
+//! The trie is built from the contract storage as it is when the execution starts. Like nearcore,
+//! the keys written or removed during the execution are then served from memory without touching
+//! the trie. Only the data of the executing account is modelled, so the nodes above it (which
+//! depend on the other accounts in the shard) are not accounted for, and the nodes touched are
+//! cached for the duration of a single execution rather than the whole chunk.
+use super::logic::TrieNodesCount;
+use super::Store;
+use near_primitives_core::types::AccountId;
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+
+/// `col::CONTRACT_DATA` in nearcore, the first byte of the trie keys of the contract storage.
+const CONTRACT_DATA: u8 = 9;
+/// `ACCOUNT_DATA_SEPARATOR` in nearcore.
+const ACCOUNT_DATA_SEPARATOR: u8 = b',';
+/// The storage proof size nearcore assumes each removal adds, since removing a key may require
+/// recording the siblings of the nodes removed.
+const REMOVAL_STORAGE_PROOF_SIZE: usize = 2000;
+const HASH_LEN: usize = 32;
+/// Length of a borsh serialized `ValueRef`: the length and the hash of the value.
+const VALUE_REF_LEN: usize = 4 + HASH_LEN;
+
+type NodeId = usize;
+
+enum Node {
+    Leaf {
+        /// The whole trie key, as nibbles.
+        key: Vec<u8>,
+        /// Number of the nibbles of the key consumed by the nodes above.
+        depth: usize,
+        value_len: usize,
+    },
+    Extension {
+        nibbles: Vec<u8>,
+        child: NodeId,
+    },
+    Branch {
+        children: [Option<NodeId>; 16],
+        value_len: Option<usize>,
+    },
+}
+
+impl Node {
+    /// Length of the node serialized as a `RawTrieNodeWithSize`, which is what gets recorded in
+    /// the storage proof.
+    fn len(&self) -> usize {
+        // The variant tag and the memory usage of the subtree.
+        let overhead = 1 + 8;
+        overhead
+            + match self {
+                Node::Leaf { key, depth, .. } => {
+                    4 + encoded_nibbles_len(key.len() - depth) + VALUE_REF_LEN
+                }
+                Node::Extension { nibbles, .. } => {
+                    4 + encoded_nibbles_len(nibbles.len()) + HASH_LEN
+                }
+                Node::Branch {
+                    children,
+                    value_len,
+                } => {
+                    let value = value_len.map_or(0, |_| VALUE_REF_LEN);
+                    value + 2 + HASH_LEN * children.iter().flatten().count()
+                }
+            }
+    }
+
+    fn value_len(&self) -> usize {
+        match self {
+            Node::Leaf { value_len, .. } => *value_len,
+            Node::Branch { value_len, .. } => value_len.unwrap_or(0),
+            Node::Extension { .. } => 0,
+        }
+    }
+}
+
+/// Length of the nibbles encoded the way `NibbleSlice::encode_nibbles` in nearcore does.
+fn encoded_nibbles_len(nibbles: usize) -> usize {
+    nibbles / 2 + 1
+}
+
+fn trie_key_nibbles(account_id: &AccountId, key: &[u8]) -> Vec<u8> {
+    let account_id = account_id.as_bytes();
+    [&[CONTRACT_DATA], account_id, &[ACCOUNT_DATA_SEPARATOR], key]
+        .concat()
+        .into_iter()
+        .flat_map(|byte| [byte >> 4, byte & 0x0f])
+        .collect()
+}
+
+/// Build the subtrie holding the `keys`, which must be sorted and share the first `depth`
+/// nibbles.
+fn build(nodes: &mut Vec<Node>, keys: &[(Vec<u8>, usize)], depth: usize) -> NodeId {
+    let node = match keys {
+        [(key, value_len)] => Node::Leaf {
+            key: key.clone(),
+            depth,
+            value_len: *value_len,
+        },
+        _ => {
+            let (first, last) = (&keys[0].0, &keys[keys.len() - 1].0);
+            let common = first[depth..]
+                .iter()
+                .zip(&last[depth..])
+                .take_while(|(a, b)| a == b)
+                .count();
+            if common > 0 {
+                let child = build(nodes, keys, depth + common);
+                Node::Extension {
+                    nibbles: first[depth..depth + common].to_vec(),
+                    child,
+                }
+            } else {
+                let (value_len, rest) = match keys {
+                    [(key, value_len), rest @ ..] if key.len() == depth => (Some(*value_len), rest),
+                    _ => (None, keys),
+                };
+                let mut children = [None; 16];
+                for (nibble, child) in (0..).zip(&mut children) {
+                    let start = rest.partition_point(|(key, _)| key[depth] < nibble);
+                    let end = rest.partition_point(|(key, _)| key[depth] <= nibble);
+                    if start < end {
+                        *child = Some(build(nodes, &rest[start..end], depth + 1));
+                    }
+                }
+                Node::Branch {
+                    children,
+                    value_len,
+                }
+            }
+        }
+    };
+    nodes.push(node);
+    nodes.len() - 1
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Item {
+    Node(NodeId),
+    /// The value held by the node.
+    Value(NodeId),
+}
+
+#[derive(Default)]
+struct Accounting {
+    /// Items that have been charged for, which nearcore serves from its cache afterwards.
+    cached: BTreeSet<Item>,
+    recorded: BTreeSet<Item>,
+    recorded_size: usize,
+    removals: usize,
+    db_reads: u64,
+    mem_reads: u64,
+    /// Keys written or removed during the execution.
+    changed: BTreeSet<Vec<u8>>,
+}
+
+pub(crate) struct TrieModel {
+    account_id: AccountId,
+    nodes: Vec<Node>,
+    root: Option<NodeId>,
+    accounting: RefCell<Accounting>,
+}
+
+impl TrieModel {
+    /// Model the trie holding the contents of the `store` of the `account_id`.
+    pub(crate) fn new(account_id: &AccountId, store: &Store) -> Self {
+        let keys = store
+            .value_lengths()
+            .into_iter()
+            .map(|(key, value_len)| (trie_key_nibbles(account_id, &key), value_len))
+            .collect::<Vec<_>>();
+        let mut nodes = vec![];
+        let root = (!keys.is_empty()).then(|| build(&mut nodes, &keys, 0));
+        Self {
+            account_id: account_id.clone(),
+            nodes,
+            root,
+            accounting: Default::default(),
+        }
+    }
+
+    /// Look the `key` up, recording the nodes on the path in the storage proof. The nodes are
+    /// charged for if `charge` is set, i.e. unless the lookup is served by the flat storage.
+    ///
+    /// Returns the value, if the lookup has found one in the trie.
+    pub(crate) fn lookup(&self, key: &[u8], charge: bool) -> Option<TrieValue<'_>> {
+        if self.accounting.borrow().changed.contains(key) {
+            return None;
+        }
+        let key = trie_key_nibbles(&self.account_id, key);
+        let mut node = self.root?;
+        let mut depth = 0;
+        loop {
+            self.access(Item::Node(node), charge);
+            match &self.nodes[node] {
+                Node::Leaf { key: leaf_key, .. } => {
+                    return (*leaf_key == key).then_some(TrieValue {
+                        trie: self,
+                        node,
+                        charge,
+                    });
+                }
+                Node::Extension { nibbles, child } => {
+                    if !key[depth..].starts_with(nibbles) {
+                        return None;
+                    }
+                    depth += nibbles.len();
+                    node = *child;
+                }
+                Node::Branch {
+                    children,
+                    value_len,
+                } => match key.get(depth) {
+                    None => {
+                        return value_len.map(|_| TrieValue {
+                            trie: self,
+                            node,
+                            charge,
+                        })
+                    }
+                    Some(&nibble) => {
+                        node = children[usize::from(nibble)]?;
+                        depth += 1;
+                    }
+                },
+            }
+        }
+    }
+
+    /// The `key` has been written or removed, so it is served from memory from now on.
+    pub(crate) fn change(&mut self, key: &[u8]) {
+        self.accounting.get_mut().changed.insert(key.to_vec());
+    }
+
+    pub(crate) fn remove(&mut self, key: &[u8]) {
+        self.change(key);
+        self.accounting.get_mut().removals += 1;
+    }
+
+    pub(crate) fn nodes_count(&self) -> TrieNodesCount {
+        let accounting = self.accounting.borrow();
+        TrieNodesCount {
+            db_reads: accounting.db_reads,
+            mem_reads: accounting.mem_reads,
+        }
+    }
+
+    /// Upper bound of the size of the storage proof recorded so far, like nearcore's
+    /// `recorded_storage_size_upper_bound`.
+    pub(crate) fn recorded_storage_size(&self) -> usize {
+        let accounting = self.accounting.borrow();
+        accounting.recorded_size + accounting.removals * REMOVAL_STORAGE_PROOF_SIZE
+    }
+
+    fn access(&self, item: Item, charge: bool) {
+        let mut accounting = self.accounting.borrow_mut();
+        if charge {
+            if accounting.cached.insert(item) {
+                accounting.db_reads += 1;
+            } else {
+                accounting.mem_reads += 1;
+            }
+        }
+        if accounting.recorded.insert(item) {
+            accounting.recorded_size += match item {
+                Item::Node(node) => self.nodes[node].len(),
+                Item::Value(node) => self.nodes[node].value_len(),
+            };
+        }
+    }
+}
+
+/// A value found in the trie by [`TrieModel::lookup`].
+pub(crate) struct TrieValue<'a> {
+    trie: &'a TrieModel,
+    node: NodeId,
+    charge: bool,
+}
+
+impl TrieValue<'_> {
+    /// Account for reading the value itself, which nearcore treats like reading another node.
+    pub(crate) fn read(&self) {
+        self.trie.access(Item::Value(self.node), self.charge);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Model the storage of `alice.near` holding the `entries`, as keys and lengths of values.
+    fn model(entries: &[(&str, usize)]) -> TrieModel {
+        let store = Store::new();
+        for (key, value_len) in entries {
+            store.set(key.as_bytes(), &vec![0; *value_len]);
+        }
+        TrieModel::new(&"alice.near".parse().unwrap(), &store)
+    }
+
+    /// Look the `key` up, reading its value if found, and return whether it has been found.
+    fn read(trie: &TrieModel, key: &str) -> bool {
+        trie.lookup(key.as_bytes(), true)
+            .map(|value| value.read())
+            .is_some()
+    }
+
+    fn counts(trie: &TrieModel) -> (u64, u64) {
+        let counts = trie.nodes_count();
+        (counts.db_reads, counts.mem_reads)
+    }
+
+    /// Number of the nibbles of the trie keys of `alice.near` before the contract storage key.
+    const PREFIX: usize = 2 * (1 + "alice.near".len() + 1);
+
+    #[test]
+    fn test_single_key() {
+        let trie = model(&[("a", 10)]);
+        assert_eq!(trie.nodes.len(), 1);
+        // The leaf holds all the nibbles of the key.
+        let leaf = 9 + 4 + ((PREFIX + 2) / 2 + 1) + VALUE_REF_LEN;
+        assert!(read(&trie, "a"));
+        assert_eq!(counts(&trie), (2, 0));
+        assert_eq!(trie.recorded_storage_size(), leaf + 10);
+        assert!(!read(&trie, "b"));
+        assert_eq!(counts(&trie), (2, 1));
+        assert_eq!(trie.recorded_storage_size(), leaf + 10);
+    }
+
+    #[test]
+    fn test_shared_prefix() {
+        let trie = model(&[("ab", 3), ("ac", 5)]);
+        // An extension with the nibbles shared up to the last one, a branch on the last nibble
+        // and the two leaves.
+        assert_eq!(trie.nodes.len(), 4);
+        let root = trie.root.unwrap();
+        let Node::Extension { nibbles, child } = &trie.nodes[root] else {
+            panic!("the root is not an extension");
+        };
+        assert_eq!(nibbles.len(), PREFIX + 3);
+        let Node::Branch {
+            children,
+            value_len,
+        } = &trie.nodes[*child]
+        else {
+            panic!("the extension does not lead to a branch");
+        };
+        assert_eq!(*value_len, None);
+        assert_eq!(children.iter().flatten().count(), 2);
+        assert!(children[2].is_some() && children[3].is_some());
+
+        let extension = 9 + 4 + ((PREFIX + 3) / 2 + 1) + HASH_LEN;
+        let branch = 9 + 2 + 2 * HASH_LEN;
+        // Nothing of the keys is left for the leaves.
+        let leaf = 9 + 4 + 1 + VALUE_REF_LEN;
+        assert!(read(&trie, "ab"));
+        assert_eq!(counts(&trie), (4, 0));
+        assert_eq!(trie.recorded_storage_size(), extension + branch + leaf + 3);
+        // The extension and the branch are cached now.
+        assert!(read(&trie, "ac"));
+        assert_eq!(counts(&trie), (6, 2));
+        let recorded = extension + branch + 2 * leaf + 3 + 5;
+        assert_eq!(trie.recorded_storage_size(), recorded);
+        // Reading the same key again is served from the cache and records nothing new.
+        assert!(read(&trie, "ab"));
+        assert_eq!(counts(&trie), (6, 6));
+        assert_eq!(trie.recorded_storage_size(), recorded);
+        assert!(!read(&trie, "ad"));
+        assert_eq!(counts(&trie), (6, 8));
+    }
+
+    #[test]
+    fn test_key_prefix_of_another_key() {
+        let trie = model(&[("a", 2), ("ab", 4)]);
+        // An extension up to the end of the shorter key, then a branch holding its value with the
+        // leaf of the longer key as its only child.
+        assert_eq!(trie.nodes.len(), 3);
+        let root = trie.root.unwrap();
+        let Node::Extension { nibbles, child } = &trie.nodes[root] else {
+            panic!("the root is not an extension");
+        };
+        assert_eq!(nibbles.len(), PREFIX + 2);
+        let Node::Branch {
+            children,
+            value_len,
+        } = &trie.nodes[*child]
+        else {
+            panic!("the extension does not lead to a branch");
+        };
+        assert_eq!(*value_len, Some(2));
+        assert_eq!(children.iter().flatten().count(), 1);
+
+        let extension = 9 + 4 + ((PREFIX + 2) / 2 + 1) + HASH_LEN;
+        let branch = 9 + VALUE_REF_LEN + 2 + HASH_LEN;
+        // The leaf holds the last nibble of the longer key.
+        let leaf = 9 + 4 + 1 + VALUE_REF_LEN;
+        assert!(read(&trie, "a"));
+        assert_eq!(counts(&trie), (3, 0));
+        assert_eq!(trie.recorded_storage_size(), extension + branch + 2);
+        assert!(read(&trie, "ab"));
+        assert_eq!(counts(&trie), (5, 2));
+        let recorded = extension + branch + leaf + 2 + 4;
+        assert_eq!(trie.recorded_storage_size(), recorded);
+        // A key going past the leaf and one diverging within the extension are not found.
+        assert!(!read(&trie, "abc"));
+        assert_eq!(counts(&trie), (5, 5));
+        assert!(!read(&trie, "b"));
+        assert_eq!(counts(&trie), (5, 6));
+        assert_eq!(trie.recorded_storage_size(), recorded);
+    }
+
+    #[test]
+    fn test_removal() {
+        let mut trie = model(&[("a", 2), ("ab", 4)]);
+        trie.remove(b"a");
+        assert_eq!(trie.recorded_storage_size(), REMOVAL_STORAGE_PROOF_SIZE);
+        // The removed key is served from memory without touching the trie.
+        assert!(!read(&trie, "a"));
+        assert_eq!(counts(&trie), (0, 0));
+        assert_eq!(trie.recorded_storage_size(), REMOVAL_STORAGE_PROOF_SIZE);
+        trie.change(b"ab");
+        assert!(!read(&trie, "ab"));
+        assert_eq!(counts(&trie), (0, 0));
+        trie.remove(b"ab");
+        assert_eq!(trie.recorded_storage_size(), 2 * REMOVAL_STORAGE_PROOF_SIZE);
+    }
+
+    #[test]
+    fn test_lookup_without_charge_is_recorded() {
+        let trie = model(&[("ab", 3), ("ac", 5)]);
+        let value = trie.lookup(b"ab", false).unwrap();
+        value.read();
+        assert_eq!(counts(&trie), (0, 0));
+        let size = trie.recorded_storage_size();
+        assert!(size > 0);
+        // The nodes are charged for when looked up through the trie later.
+        assert!(read(&trie, "ab"));
+        assert_eq!(counts(&trie), (4, 0));
+        assert_eq!(trie.recorded_storage_size(), size);
+    }
+
+    #[test]
+    fn test_empty_store() {
+        let trie = model(&[]);
+        assert!(trie.root.is_none());
+        assert!(!read(&trie, "a"));
+        assert_eq!(counts(&trie), (0, 0));
+        assert_eq!(trie.recorded_storage_size(), 0);
+    }
+}
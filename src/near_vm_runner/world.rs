@@ -0,0 +1,593 @@
+//! State of all the accounts known to the debugger.
+//!
+//! Each account has its own balance, code, access keys and contract storage, much like the
+//! `Account`, `AccessKey` and contract data records nearcore keeps in its state trie.
+use super::scheduler::PendingReceipt;
+use super::{js_serializer, Result, Store};
+use js_sys::Uint8Array;
+use near_crypto::PublicKey;
+use near_parameters::StorageUsageConfig;
+use near_primitives_core::hash::CryptoHash;
+use near_primitives_core::types::{
+    AccountId, Balance, BlockHeight, EpochHeight, Nonce, StorageUsage,
+};
+use serde::Serialize as _;
+use serde_with::{serde_as, DisplayFromStr};
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::{Arc, Mutex, MutexGuard};
+use wasm_bindgen::prelude::*;
+
+#[serde_as]
+#[derive(Clone, serde::Serialize, serde::Deserialize, borsh::BorshSerialize)]
+pub(crate) enum AccessKeyPermission {
+    FunctionCall {
+        #[serde_as(as = "Option<DisplayFromStr>")]
+        allowance: Option<Balance>,
+        receiver_id: AccountId,
+        method_names: Vec<String>,
+    },
+    FullAccess,
+}
+
+/// Serialized with borsh the same way as nearcore's `AccessKey`, so that it takes up as much
+/// storage.
+#[derive(Clone, serde::Serialize, serde::Deserialize, borsh::BorshSerialize)]
+pub(crate) struct AccessKey {
+    pub(crate) nonce: Nonce,
+    pub(crate) permission: AccessKeyPermission,
+}
+
+#[serde_as]
+#[derive(serde::Serialize, serde::Deserialize)]
+pub(crate) struct Account {
+    #[serde_as(as = "DisplayFromStr")]
+    pub(crate) amount: Balance,
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(default)]
+    pub(crate) locked: Balance,
+    #[serde(default)]
+    pub(crate) storage_usage: StorageUsage,
+    #[serde_as(as = "Option<serde_with::base64::Base64>")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) code: Option<Arc<[u8]>>,
+    #[serde(default)]
+    pub(crate) access_keys: BTreeMap<PublicKey, AccessKey>,
+    #[serde(default)]
+    pub(crate) storage: Store,
+}
+
+impl Account {
+    fn new(amount: Balance) -> Self {
+        Self {
+            amount,
+            locked: 0,
+            storage_usage: 0,
+            code: None,
+            access_keys: BTreeMap::new(),
+            storage: Store::new(),
+        }
+    }
+
+    pub(crate) fn code_hash(&self) -> CryptoHash {
+        match &self.code {
+            Some(code) => CryptoHash::hash_bytes(code),
+            None => CryptoHash::default(),
+        }
+    }
+
+    /// The storage usage of the account as nearcore computes it: the account record itself, the
+    /// contract code, the access keys and the contract storage entries.
+    pub(crate) fn compute_storage_usage(&self, config: &StorageUsageConfig) -> StorageUsage {
+        self.storage_usage_without_data(config)
+            + self.storage.storage_usage(config.num_extra_bytes_record)
+    }
+
+    /// Like [`Self::compute_storage_usage`], without the contract storage entries.
+    pub(crate) fn storage_usage_without_data(&self, config: &StorageUsageConfig) -> StorageUsage {
+        let code = self.code.as_ref().map_or(0, |code| code.len() as u64);
+        let access_keys = self
+            .access_keys
+            .iter()
+            .map(|(public_key, access_key)| {
+                let access_key = borsh::object_length(access_key).expect("serializing to a Vec");
+                public_key.len() as u64 + access_key as u64 + config.num_extra_bytes_record
+            })
+            .sum::<StorageUsage>();
+        config.num_bytes_account + code + access_keys
+    }
+
+    /// Copy of the account that does not share the contract storage with the original, so that
+    /// the original can be restored if the changes made to it need to be reverted.
+    pub(crate) fn snapshot(&self) -> Self {
+        Self {
+            amount: self.amount,
+            locked: self.locked,
+            storage_usage: self.storage_usage,
+            code: self.code.clone(),
+            access_keys: self.access_keys.clone(),
+            storage: self.storage.deep_clone(),
+        }
+    }
+}
+
+/// A receipt created by `promise_yield_create` that is waiting to be resumed or to time out.
+pub(crate) struct YieldedReceipt {
+    /// The data `promise_yield_resume` delivers to the receipt.
+    pub(crate) data_id: CryptoHash,
+    /// The block height at which the receipt times out.
+    pub(crate) expires_at: BlockHeight,
+    pub(crate) pending: PendingReceipt,
+}
+
+#[derive(serde::Serialize)]
+struct YieldInfo<'a> {
+    data_id: &'a CryptoHash,
+    account_id: &'a AccountId,
+    expires_at: BlockHeight,
+}
+
+#[serde_as]
+#[derive(Default, serde::Serialize, serde::Deserialize)]
+struct WorldState {
+    accounts: BTreeMap<AccountId, Account>,
+    /// The validators and their stakes, by the epoch they have been set for. The validators of an
+    /// epoch remain the same in the following epochs, until another set is defined.
+    #[serde_as(as = "BTreeMap<_, BTreeMap<_, DisplayFromStr>>")]
+    #[serde(default)]
+    validators: BTreeMap<EpochHeight, BTreeMap<AccountId, Balance>>,
+    /// The stakes lowered by `Stake` actions, by the epoch the tokens no longer staked are returned
+    /// to the accounts in.
+    #[serde_as(as = "BTreeMap<_, BTreeMap<_, DisplayFromStr>>")]
+    #[serde(default)]
+    unstakes: BTreeMap<EpochHeight, BTreeMap<AccountId, Balance>>,
+    /// NB: these are not saved to the `.nearstore` files.
+    #[serde(skip)]
+    yields: Vec<YieldedReceipt>,
+}
+
+/// The formats of the `.nearstore` files that can be loaded.
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+enum WorldFile {
+    World(WorldState),
+    /// Earlier versions of the debugger only stored the storage of a single contract.
+    Storage(Store),
+}
+
+/// An account as it was when [`World::snapshot`] was called.
+#[wasm_bindgen]
+pub struct AccountSnapshot {
+    account_id: AccountId,
+    /// `None` if the account did not exist.
+    account: Option<Account>,
+}
+
+#[wasm_bindgen]
+#[derive(Clone, Default)]
+pub struct World(Arc<Mutex<WorldState>>);
+
+#[wasm_bindgen]
+impl World {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> Self {
+        Self(Default::default())
+    }
+
+    /// Load the state from a `.nearstore` file.
+    ///
+    /// Files that hold just the contract storage are loaded as the storage of `default_account`.
+    pub fn from_json(array: Uint8Array, default_account: &str) -> Result<Self> {
+        Ok(Self::from_slice(
+            &array.to_vec(),
+            &default_account.parse()?,
+        )?)
+    }
+
+    fn guard(&self) -> MutexGuard<WorldState> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn to_json(&self) -> Result<Uint8Array> {
+        let json = self.to_vec()?;
+        let value = Uint8Array::new_with_length(json.len() as u32);
+        value.copy_from(&json);
+        Ok(value)
+    }
+
+    pub fn to_value(&self) -> Result<JsValue> {
+        self.guard().serialize(&js_serializer()).map_err(Into::into)
+    }
+
+    /// The ids of all the accounts that exist.
+    pub fn accounts(&self) -> Vec<String> {
+        self.guard()
+            .accounts
+            .keys()
+            .map(|id| id.to_string())
+            .collect()
+    }
+
+    pub fn has_account(&self, account_id: &str) -> Result<bool> {
+        Ok(self.guard().accounts.contains_key(&account_id.parse()?))
+    }
+
+    /// Create the account if it does not exist yet.
+    pub fn create_account(&self, account_id: &str, amount: &str) -> Result<()> {
+        let amount = amount.parse()?;
+        self.guard()
+            .accounts
+            .entry(account_id.parse()?)
+            .or_insert_with(|| Account::new(amount));
+        Ok(())
+    }
+
+    pub fn delete_account(&self, account_id: &str) -> Result<()> {
+        self.guard().accounts.remove(&account_id.parse()?);
+        Ok(())
+    }
+
+    pub fn set_balance(&self, account_id: &str, amount: &str, locked: &str) -> Result<()> {
+        let (amount, locked) = (amount.parse()?, locked.parse()?);
+        self.update(account_id, |account| {
+            account.amount = amount;
+            account.locked = locked;
+        })
+    }
+
+    /// Deploy the contract `code` to the account, creating it if necessary.
+    pub fn deploy(&self, account_id: &str, code: &[u8]) -> Result<()> {
+        let mut state = self.guard();
+        let account = state
+            .accounts
+            .entry(account_id.parse()?)
+            .or_insert_with(|| Account::new(0));
+        account.code = Some(code.into());
+        Ok(())
+    }
+
+    pub fn code(&self, account_id: &str) -> Result<Option<Vec<u8>>> {
+        self.read(account_id, |account| {
+            account.code.as_deref().map(<[u8]>::to_vec)
+        })
+    }
+
+    pub fn code_hash(&self, account_id: &str) -> Result<String> {
+        self.read(account_id, |account| account.code_hash().to_string())
+    }
+
+    /// The contract storage of the account.
+    pub fn store(&self, account_id: &str) -> Result<Store> {
+        self.read(account_id, |account| account.storage.clone())
+    }
+
+    pub fn add_full_access_key(&self, account_id: &str, public_key: &str) -> Result<()> {
+        let public_key: PublicKey = public_key.parse()?;
+        self.update(account_id, |account| {
+            let key = AccessKey {
+                nonce: 0,
+                permission: AccessKeyPermission::FullAccess,
+            };
+            account.access_keys.insert(public_key, key);
+        })
+    }
+
+    /// Add an access key that only permits calling the `method_names` (or any method, if empty)
+    /// of the `receiver_id`, paying at most the `allowance` (unlimited if not given) for gas.
+    pub fn add_function_call_key(
+        &self,
+        account_id: &str,
+        public_key: &str,
+        allowance: Option<String>,
+        receiver_id: &str,
+        method_names: Vec<String>,
+    ) -> Result<()> {
+        let public_key: PublicKey = public_key.parse()?;
+        let permission = AccessKeyPermission::FunctionCall {
+            allowance: allowance.map(|allowance| allowance.parse()).transpose()?,
+            receiver_id: receiver_id.parse()?,
+            method_names,
+        };
+        self.update(account_id, |account| {
+            let key = AccessKey {
+                nonce: 0,
+                permission,
+            };
+            account.access_keys.insert(public_key, key);
+        })
+    }
+
+    /// The receipts waiting to be resumed or to time out, with the `data_id` they are resumed
+    /// with, the account that can resume them and the block height at which they time out.
+    pub fn yields(&self) -> Result<JsValue> {
+        let state = self.guard();
+        let yields = state
+            .yields
+            .iter()
+            .map(|yielded| YieldInfo {
+                data_id: &yielded.data_id,
+                account_id: &yielded.pending.receipt.receiver_id,
+                expires_at: yielded.expires_at,
+            })
+            .collect::<Vec<_>>();
+        yields.serialize(&js_serializer()).map_err(Into::into)
+    }
+
+    /// Set the stake of the validator in the epoch, and the following epochs until another set of
+    /// validators is defined. Zero stake removes the validator.
+    pub fn set_validator_stake(
+        &self,
+        epoch_height: &str,
+        account_id: &str,
+        stake: &str,
+    ) -> Result<()> {
+        self.update_validator_stake(epoch_height.parse()?, account_id.parse()?, stake.parse()?);
+        Ok(())
+    }
+
+    /// Start the epoch: return the tokens of the stakes lowered to take effect by then to the
+    /// accounts, like nearcore does at the epoch boundary.
+    pub fn start_epoch(&self, epoch_height: &str) -> Result<()> {
+        self.release_unstaked(epoch_height.parse()?);
+        Ok(())
+    }
+
+    /// The validators of the epoch, along with their stakes.
+    pub fn validators(&self, epoch_height: &str) -> Result<JsValue> {
+        let validators = self
+            .validators_at(epoch_height.parse()?)
+            .into_iter()
+            .map(|(account_id, stake)| (account_id.to_string(), stake.to_string()))
+            .collect::<BTreeMap<_, _>>();
+        validators.serialize(&js_serializer()).map_err(Into::into)
+    }
+
+    /// Take a snapshot of the account, so that the changes made to it (e.g. by a call that then
+    /// fails) can be reverted with [`Self::restore`].
+    pub fn snapshot(&self, account_id: &str) -> Result<AccountSnapshot> {
+        let account_id: AccountId = account_id.parse()?;
+        let account = self.with_account(&account_id, |account| account.snapshot());
+        Ok(AccountSnapshot {
+            account_id,
+            account,
+        })
+    }
+
+    /// Put back the account as it was when the `snapshot` was taken.
+    pub fn restore(&self, snapshot: AccountSnapshot) {
+        self.restore_account(&snapshot.account_id, snapshot.account);
+    }
+
+    pub fn delete_key(&self, account_id: &str, public_key: &str) -> Result<()> {
+        let public_key: PublicKey = public_key.parse()?;
+        self.update(account_id, |account| {
+            account.access_keys.remove(&public_key);
+        })
+    }
+}
+
+impl World {
+    /// Load the state from the contents of a `.nearstore` file. See [`Self::from_json`].
+    pub(crate) fn from_slice(
+        bytes: &[u8],
+        default_account: &AccountId,
+    ) -> serde_json::Result<Self> {
+        let state = match serde_json::from_slice(bytes)? {
+            WorldFile::World(state) => state,
+            WorldFile::Storage(storage) => {
+                let mut account = Account::new(0);
+                account.storage = storage;
+                WorldState {
+                    accounts: [(default_account.clone(), account)].into(),
+                    ..Default::default()
+                }
+            }
+        };
+        Ok(Self(Arc::new(Mutex::new(state))))
+    }
+
+    pub(crate) fn to_vec(&self) -> serde_json::Result<Vec<u8>> {
+        serde_json::to_vec(&*self.guard())
+    }
+
+    fn read<R>(&self, account_id: &str, f: impl FnOnce(&Account) -> R) -> Result<R> {
+        let account_id: AccountId = account_id.parse()?;
+        self.with_account(&account_id, |account| f(account))
+            .ok_or_else(|| JsError::new(&format!("account {account_id} does not exist")))
+    }
+
+    fn update(&self, account_id: &str, f: impl FnOnce(&mut Account)) -> Result<()> {
+        let account_id: AccountId = account_id.parse()?;
+        self.with_account(&account_id, f)
+            .ok_or_else(|| JsError::new(&format!("account {account_id} does not exist")))
+    }
+
+    /// Access the account, if it exists.
+    pub(crate) fn with_account<R>(
+        &self,
+        account_id: &AccountId,
+        f: impl FnOnce(&mut Account) -> R,
+    ) -> Option<R> {
+        self.guard().accounts.get_mut(account_id).map(f)
+    }
+
+    pub(crate) fn remove_account(&self, account_id: &AccountId) -> Option<Account> {
+        self.guard().accounts.remove(account_id)
+    }
+
+    /// Put back the account as it was when the `snapshot` was taken. `None` signifies the account
+    /// did not exist at the time.
+    pub(crate) fn restore_account(&self, account_id: &AccountId, snapshot: Option<Account>) {
+        let mut state = self.guard();
+        let Some(snapshot) = snapshot else {
+            state.accounts.remove(account_id);
+            return;
+        };
+        match state.accounts.get_mut(account_id) {
+            // Restore the storage in place, so that the existing handles to it remain valid.
+            Some(account) => {
+                account.storage.restore(&snapshot.storage);
+                *account = Account {
+                    storage: account.storage.clone(),
+                    ..snapshot
+                };
+            }
+            None => {
+                state.accounts.insert(account_id.clone(), snapshot);
+            }
+        }
+    }
+
+    /// The validators of the epoch, along with their stakes.
+    pub(crate) fn validators_at(&self, epoch_height: EpochHeight) -> BTreeMap<AccountId, Balance> {
+        self.guard()
+            .validators
+            .range(..=epoch_height)
+            .next_back()
+            .map(|(_, validators)| validators.clone())
+            .unwrap_or_default()
+    }
+
+    /// See [`Self::set_validator_stake`].
+    pub(crate) fn update_validator_stake(
+        &self,
+        epoch_height: EpochHeight,
+        account_id: AccountId,
+        stake: Balance,
+    ) {
+        let mut validators = self.validators_at(epoch_height);
+        if stake == 0 {
+            validators.remove(&account_id);
+        } else {
+            validators.insert(account_id, stake);
+        }
+        self.guard().validators.insert(epoch_height, validators);
+    }
+
+    /// Lower the locked balance of the account to the stake once the epoch starts, in place of
+    /// the stake lowered earlier, if any. `None` cancels the lowering of the stake.
+    pub(crate) fn schedule_unstake(
+        &self,
+        account_id: &AccountId,
+        unstake: Option<(EpochHeight, Balance)>,
+    ) {
+        let mut state = self.guard();
+        for stakes in state.unstakes.values_mut() {
+            stakes.remove(account_id);
+        }
+        state.unstakes.retain(|_, stakes| !stakes.is_empty());
+        if let Some((epoch_height, stake)) = unstake {
+            let stakes = state.unstakes.entry(epoch_height).or_default();
+            stakes.insert(account_id.clone(), stake);
+        }
+    }
+
+    /// Return the tokens no longer staked to the accounts, for the stakes lowered to take effect
+    /// by the epoch `epoch_height`.
+    pub(crate) fn release_unstaked(&self, epoch_height: EpochHeight) {
+        let mut state = self.guard();
+        let later = state.unstakes.split_off(&(epoch_height + 1));
+        let released = std::mem::replace(&mut state.unstakes, later);
+        for (account_id, stake) in released.into_values().flatten() {
+            if let Some(account) = state.accounts.get_mut(&account_id) {
+                let unstaked = account.locked.saturating_sub(stake);
+                account.locked -= unstaked;
+                account.amount += unstaked;
+            }
+        }
+    }
+
+    pub(crate) fn insert_yield(&self, yielded: YieldedReceipt) {
+        self.guard().yields.push(yielded);
+    }
+
+    /// Take the yielded receipts matching the `filter` out of the world.
+    pub(crate) fn take_yields(
+        &self,
+        filter: impl Fn(&YieldedReceipt) -> bool,
+    ) -> Vec<YieldedReceipt> {
+        let mut state = self.guard();
+        let (taken, kept): (Vec<_>, _) = std::mem::take(&mut state.yields)
+            .into_iter()
+            .partition(filter);
+        state.yields = kept;
+        taken
+    }
+
+    /// The `data_id`s of the receipts yielded by the account.
+    pub(crate) fn yielded_data_ids(&self, account_id: &AccountId) -> BTreeSet<CryptoHash> {
+        self.guard()
+            .yields
+            .iter()
+            .filter(|yielded| yielded.pending.receipt.receiver_id == *account_id)
+            .map(|yielded| yielded.data_id)
+            .collect()
+    }
+
+    /// Insert a new account. Returns `false` if the account already exists.
+    pub(crate) fn insert_account(&self, account_id: &AccountId, amount: Balance) -> bool {
+        let mut state = self.guard();
+        if state.accounts.contains_key(account_id) {
+            return false;
+        }
+        state
+            .accounts
+            .insert(account_id.clone(), Account::new(amount));
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::near_vm_runner::RuntimeConfig;
+    use near_crypto::KeyType;
+    use near_primitives_core::version::PROTOCOL_VERSION;
+
+    fn storage_usage_config() -> StorageUsageConfig {
+        let config = RuntimeConfig::new(PROTOCOL_VERSION);
+        config.fees().storage_usage_config.clone()
+    }
+
+    #[test]
+    fn test_storage_usage_of_empty_account() {
+        let config = storage_usage_config();
+        assert_eq!(config.num_bytes_account, 100);
+        assert_eq!(config.num_extra_bytes_record, 40);
+        assert_eq!(Account::new(0).compute_storage_usage(&config), 100);
+    }
+
+    #[test]
+    fn test_storage_usage_matches_nearcore() {
+        let config = storage_usage_config();
+        let mut account = Account::new(0);
+        account.code = Some(vec![0; 100].into());
+        // 33 bytes of the key, 8 of the nonce and 1 of the permission.
+        let full_access = AccessKey {
+            nonce: 1,
+            permission: AccessKeyPermission::FullAccess,
+        };
+        account
+            .access_keys
+            .insert(PublicKey::empty(KeyType::ED25519), full_access);
+        // 65 bytes of the key, 8 of the nonce, 1 of the permission, 17 of the allowance, 14 of
+        // the receiver and 9 of the method names.
+        let function_call = AccessKey {
+            nonce: 2,
+            permission: AccessKeyPermission::FunctionCall {
+                allowance: Some(1),
+                receiver_id: "alice.near".parse().unwrap(),
+                method_names: vec!["a".to_string()],
+            },
+        };
+        account
+            .access_keys
+            .insert(PublicKey::empty(KeyType::SECP256K1), function_call);
+        account.storage.set(b"key", b"value");
+        let without_data = 100 + 100 + (33 + 9 + 40) + (65 + 49 + 40);
+        assert_eq!(account.storage_usage_without_data(&config), without_data);
+        assert_eq!(
+            account.compute_storage_usage(&config),
+            without_data + (3 + 5 + 40)
+        );
+    }
+}
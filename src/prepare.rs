@@ -1,19 +1,158 @@
+use crate::near_vm_runner::logic::errors::{FunctionCallError, PrepareError};
+use crate::near_vm_runner::{host_function_type, HostFunctionType};
 use finite_wasm::prefix_sum_vec;
 use finite_wasm::wasmparser as wp;
-use wasm_bindgen::JsError;
+use near_parameters::vm::Config;
+use std::fmt;
 use wasm_encoder::{Encode, Section, SectionId};
 
-struct LimitConfig {
-    max_functions_number_per_contract: Option<u64>,
-    max_locals_per_contract: Option<u64>,
-    initial_memory_pages: u32,
-    max_memory_pages: u32,
+/// Why preparing a contract has failed.
+#[derive(Debug, Clone)]
+pub(crate) struct Error {
+    /// The error nearcore reports for the contract, usually a `PrepareError`.
+    pub(crate) function_call_error: FunctionCallError,
+    /// What exactly is wrong with the contract.
+    pub(crate) message: String,
+    /// Offset into the contract code where the problem has been found, if known.
+    pub(crate) offset: Option<usize>,
+    /// Index of the function the problem has been found in, if known.
+    pub(crate) function_index: Option<u32>,
+    /// The imports the runtime would fail to link the contract with, if that is the problem.
+    pub(crate) imports: Vec<ImportError>,
 }
 
-struct Config {
-    limit_config: LimitConfig,
-    discard_custom_sections: bool,
-    regular_op_cost: u64,
+/// An import of a function the runtime does not provide, or provides with a different signature.
+#[derive(Debug, Clone, serde::Serialize)]
+pub(crate) struct ImportError {
+    /// Name of the imported function.
+    pub(crate) name: String,
+    /// What is wrong with the import.
+    pub(crate) message: String,
+    /// Offset of the import into the contract code.
+    pub(crate) offset: usize,
+}
+
+impl Error {
+    fn new(error: PrepareError, message: impl Into<String>) -> Self {
+        Self {
+            function_call_error: error.into(),
+            message: message.into(),
+            offset: None,
+            function_index: None,
+            imports: Vec::new(),
+        }
+    }
+
+    /// The contract imports functions the runtime would fail to link.
+    ///
+    /// nearcore only finds these when instantiating the contract, so the contract is reported with
+    /// the `LinkError` the runtime would fail with rather than a `PrepareError`.
+    fn link(imports: Vec<ImportError>) -> Self {
+        let message = "the contract imports functions the runtime does not provide";
+        let mut error = Self {
+            imports,
+            ..Self::new(PrepareError::Instantiate, message)
+        };
+        error.offset = error.imports.first().map(|import| import.offset);
+        error.function_call_error = FunctionCallError::LinkError {
+            msg: error.to_string(),
+        };
+        error
+    }
+
+    fn at(mut self, offset: usize) -> Self {
+        self.offset = Some(offset);
+        self
+    }
+
+    fn in_function(mut self, index: u32) -> Self {
+        self.function_index = Some(index);
+        self
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)?;
+        if !self.imports.is_empty() {
+            let imports = self
+                .imports
+                .iter()
+                .map(|import| format!("{} at offset {:#x}", import.message, import.offset));
+            return write!(f, ": {}", imports.collect::<Vec<_>>().join("; "));
+        }
+        if let Some(index) = self.function_index {
+            write!(f, " in function {index}")?;
+        }
+        if let Some(offset) = self.offset {
+            write!(f, " at offset {offset:#x}")?;
+        }
+        Ok(())
+    }
+}
+
+/// The contract is not valid WebAssembly, or uses features the runtime does not support.
+fn invalid(context: &'static str) -> impl FnOnce(wp::BinaryReaderError) -> Error {
+    move |error| {
+        Error::new(
+            PrepareError::Deserialization,
+            format!("{context}: {}", error.message()),
+        )
+        .at(error.offset())
+    }
+}
+
+/// The functions and locals of a contract, as limited by the runtime.
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct Counts {
+    /// Both imported and defined by the contract.
+    pub(crate) functions: u64,
+    /// Declared by all of the functions together.
+    pub(crate) locals: u64,
+}
+
+impl Counts {
+    /// Count the import, if it imports a function.
+    fn import(&mut self, ty: &wp::TypeRef) {
+        if let wp::TypeRef::Func(_) = ty {
+            self.functions = self.functions.saturating_add(1);
+        }
+    }
+
+    /// Count the `count` functions the code section defines.
+    fn code_section(&mut self, count: u32) {
+        self.functions = self.functions.saturating_add(u64::from(count));
+    }
+
+    /// Count the locals declared by the function.
+    fn locals(&mut self, func: &wp::FunctionBody) -> Result<(), Error> {
+        for local in func
+            .get_locals_reader()
+            .map_err(invalid("could not parse locals"))?
+        {
+            let (count, _ty) = local.map_err(invalid("could not parse locals"))?;
+            self.locals = self.locals.saturating_add(u64::from(count));
+        }
+        Ok(())
+    }
+}
+
+/// Count the functions and the locals of the contract `code`, without validating it.
+pub(crate) fn count_functions_and_locals(code: &[u8]) -> Result<Counts, Error> {
+    let mut counts = Counts::default();
+    for payload in wp::Parser::new(0).parse_all(code) {
+        match payload.map_err(invalid("could not parse webassembly"))? {
+            wp::Payload::ImportSection(reader) => {
+                for import in reader {
+                    counts.import(&import.map_err(invalid("could not parse an import"))?.ty);
+                }
+            }
+            wp::Payload::CodeSectionStart { count, .. } => counts.code_section(count),
+            wp::Payload::CodeSectionEntry(func) => counts.locals(&func)?,
+            _ => {}
+        }
+    }
+    Ok(counts)
 }
 
 struct PrepareContext<'a> {
@@ -22,7 +161,13 @@ struct PrepareContext<'a> {
     output_code: Vec<u8>,
     function_limit: u64,
     local_limit: u64,
+    /// The functions and locals encountered so far, to be checked against the limits.
+    counts: Counts,
     validator: wp::Validator,
+    /// Index of the function the next code section entry defines.
+    function_index: u32,
+    /// The types declared by the type section, for checking the imported functions against.
+    types: Vec<wp::Type>,
     func_validator_allocations: wp::FuncValidatorAllocations,
     before_import_section: bool,
 }
@@ -38,7 +183,10 @@ impl<'a> PrepareContext<'a> {
             // specified, use that as a limit.
             function_limit: limits.max_functions_number_per_contract.unwrap_or(u64::MAX),
             local_limit: limits.max_locals_per_contract.unwrap_or(u64::MAX),
+            counts: Counts::default(),
             validator: wp::Validator::new_with_features(features.into()),
+            function_index: 0,
+            types: Vec::new(),
             func_validator_allocations: wp::FuncValidatorAllocations::default(),
             before_import_section: true,
         }
@@ -50,12 +198,11 @@ impl<'a> PrepareContext<'a> {
     /// applicable to other runtimes.
     ///
     /// This will validate the module, normalize the memories within, apply limits.
-    fn run(&mut self) -> Result<Vec<u8>, JsError> {
+    fn run(&mut self) -> Result<Vec<u8>, Error> {
         self.before_import_section = true;
         let parser = wp::Parser::new(0);
         for payload in parser.parse_all(self.code) {
-            let payload = payload
-                .map_err(|err| JsError::new(&format!("could not parse webassembly: {err}")))?;
+            let payload = payload.map_err(invalid("could not parse webassembly"))?;
             match payload {
                 wp::Payload::Version {
                     num,
@@ -63,95 +210,97 @@ impl<'a> PrepareContext<'a> {
                     range,
                 } => {
                     self.copy(range.clone())?;
-                    self.validator.version(num, encoding, &range).map_err(|e| {
-                        JsError::new(&format!("could not validate webassembly: {e}"))
-                    })?;
+                    self.validator
+                        .version(num, encoding, &range)
+                        .map_err(invalid("could not validate webassembly"))?;
                 }
                 wp::Payload::End(offset) => {
-                    self.validator.end(offset).map_err(|e| {
-                        JsError::new(&format!("could not validate webassembly: {e}"))
-                    })?;
+                    self.validator
+                        .end(offset)
+                        .map_err(invalid("could not validate webassembly"))?;
                 }
 
                 wp::Payload::TypeSection(reader) => {
-                    self.validator.type_section(&reader).map_err(|e| {
-                        JsError::new(&format!("could not validate type section: {e}"))
-                    })?;
+                    self.validator
+                        .type_section(&reader)
+                        .map_err(invalid("could not validate type section"))?;
+                    for ty in reader.clone() {
+                        self.types
+                            .push(ty.map_err(invalid("could not parse a type"))?);
+                    }
                     self.copy_section(SectionId::Type, reader.range())?;
                 }
 
                 wp::Payload::ImportSection(reader) => {
                     self.before_import_section = false;
-                    self.validator.import_section(&reader).map_err(|e| {
-                        JsError::new(&format!("could not validate import section: {e}"))
-                    })?;
+                    self.validator
+                        .import_section(&reader)
+                        .map_err(invalid("could not validate import section"))?;
                     self.transform_import_section(&reader)?;
                 }
 
                 wp::Payload::FunctionSection(reader) => {
                     self.ensure_import_section();
-                    self.validator.function_section(&reader).map_err(|e| {
-                        JsError::new(&format!("could not validate function section: {e}"))
-                    })?;
+                    self.validator
+                        .function_section(&reader)
+                        .map_err(invalid("could not validate function section"))?;
                     self.copy_section(SectionId::Function, reader.range())?;
                 }
                 wp::Payload::TableSection(reader) => {
                     self.ensure_import_section();
-                    self.validator.table_section(&reader).map_err(|e| {
-                        JsError::new(&format!("could not validate table section: {e}"))
-                    })?;
+                    self.validator
+                        .table_section(&reader)
+                        .map_err(invalid("could not validate table section"))?;
                     self.copy_section(SectionId::Table, reader.range())?;
                 }
                 wp::Payload::MemorySection(reader) => {
                     // We do not want to include the implicit memory anymore as we normalized it by
                     // importing the memory instead.
                     self.ensure_import_section();
-                    self.validator.memory_section(&reader).map_err(|e| {
-                        JsError::new(&format!("could not validate memory section: {e}"))
-                    })?;
+                    self.validator
+                        .memory_section(&reader)
+                        .map_err(invalid("could not validate memory section"))?;
                 }
                 wp::Payload::GlobalSection(reader) => {
                     self.ensure_import_section();
-                    self.validator.global_section(&reader).map_err(|e| {
-                        JsError::new(&format!("could not validate globals section: {e}"))
-                    })?;
+                    self.validator
+                        .global_section(&reader)
+                        .map_err(invalid("could not validate globals section"))?;
                     self.copy_section(SectionId::Global, reader.range())?;
                 }
                 wp::Payload::ExportSection(reader) => {
                     self.ensure_import_section();
-                    self.validator.export_section(&reader).map_err(|e| {
-                        JsError::new(&format!("could not validate exports section: {e}"))
-                    })?;
+                    self.validator
+                        .export_section(&reader)
+                        .map_err(invalid("could not validate exports section"))?;
                     self.copy_section(SectionId::Export, reader.range())?;
                 }
                 wp::Payload::StartSection { func, range } => {
                     self.ensure_import_section();
-                    self.validator.start_section(func, &range).map_err(|e| {
-                        JsError::new(&format!("could not validate start section: {e}"))
-                    })?;
+                    self.validator
+                        .start_section(func, &range)
+                        .map_err(invalid("could not validate start section"))?;
                     self.copy_section(SectionId::Start, range.clone())?;
                 }
                 wp::Payload::ElementSection(reader) => {
                     self.ensure_import_section();
-                    self.validator.element_section(&reader).map_err(|e| {
-                        JsError::new(&format!("could not validate elements section: {e}"))
-                    })?;
+                    self.validator
+                        .element_section(&reader)
+                        .map_err(invalid("could not validate elements section"))?;
                     self.copy_section(SectionId::Element, reader.range())?;
                 }
                 wp::Payload::DataCountSection { count, range } => {
                     self.ensure_import_section();
                     self.validator
                         .data_count_section(count, &range)
-                        .map_err(|e| {
-                            JsError::new(&format!("could not validate data count section: {e}"))
-                        })?;
+                        .map_err(invalid("could not validate data count section"))?;
                     self.copy_section(SectionId::DataCount, range.clone())?;
                 }
                 wp::Payload::DataSection(reader) => {
                     self.ensure_import_section();
-                    self.validator.data_section(&reader).map_err(|e| {
-                        JsError::new(&format!("could not validate data section: {e}"))
-                    })?;
+                    self.validator
+                        .data_section(&reader)
+                        .map_err(invalid("could not validate data section"))?;
                     self.copy_section(SectionId::Data, reader.range())?;
                 }
                 wp::Payload::CodeSectionStart {
@@ -160,40 +309,39 @@ impl<'a> PrepareContext<'a> {
                     range,
                 } => {
                     self.ensure_import_section();
-                    self.function_limit = self
-                        .function_limit
-                        .checked_sub(u64::from(count))
-                        .ok_or_else(|| {
-                            JsError::new(&format!("module contains too many functions!"))
-                        })?;
+                    self.counts.code_section(count);
+                    if self.counts.functions > self.function_limit {
+                        let message = "the contract contains too many functions";
+                        return Err(
+                            Error::new(PrepareError::TooManyFunctions, message).at(range.start)
+                        );
+                    }
                     self.validator
                         .code_section_start(count, &range)
-                        .map_err(|e| {
-                            JsError::new(&format!("could not validate code section start: {e}"))
-                        })?;
+                        .map_err(invalid("could not validate code section start"))?;
                     self.copy_section(SectionId::Code, range.clone())?;
                 }
                 wp::Payload::CodeSectionEntry(func) => {
-                    let local_reader = func.get_locals_reader().map_err(|e| {
-                        JsError::new(&format!("could not parse parse locals for function {e}"))
-                    })?;
-                    for local in local_reader {
-                        let (count, _ty) = local.map_err(|e| {
-                            JsError::new(&format!("could not parse locals for function: {e}"))
-                        })?;
-                        self.local_limit = self
-                            .local_limit
-                            .checked_sub(u64::from(count))
-                            .ok_or_else(|| {
-                                JsError::new(&format!(
-                                    "a function contains too many ({count}) locals"
-                                ))
-                            })?;
+                    let function_index = self.function_index;
+                    self.function_index += 1;
+                    self.counts
+                        .locals(&func)
+                        .map_err(|e| e.in_function(function_index))?;
+                    if self.counts.locals > self.local_limit {
+                        let message = format!(
+                            "the contract declares too many locals ({} in total)",
+                            self.counts.locals
+                        );
+                        return Err(Error::new(PrepareError::TooManyLocals, message)
+                            .in_function(function_index)
+                            .at(func.range().start));
                     }
 
-                    let func_validator = self.validator.code_section_entry(&func).map_err(|e| {
-                        JsError::new(&format!("could not validate code section entry: {e}"))
-                    })?;
+                    let func_validator = self
+                        .validator
+                        .code_section_entry(&func)
+                        .map_err(invalid("could not validate code section entry"))
+                        .map_err(|e| e.in_function(function_index))?;
                     // PANIC-SAFETY: no big deal if we panic here while the allocations are taken.
                     // Worst-case we are going to be making new allocations again, but in practice
                     // this should never happen as this context should not be reused.
@@ -204,7 +352,8 @@ impl<'a> PrepareContext<'a> {
                     let mut func_validator = func_validator.into_validator(allocs);
                     func_validator
                         .validate(&func)
-                        .map_err(|e| JsError::new(&format!("could not validate function: {e}")))?;
+                        .map_err(invalid("could not validate function"))
+                        .map_err(|e| e.in_function(function_index))?;
                     self.func_validator_allocations = func_validator.into_allocations();
                 }
                 wp::Payload::CustomSection(reader) => {
@@ -228,46 +377,87 @@ impl<'a> PrepareContext<'a> {
                 | wp::Payload::ComponentStartSection { .. }
                 | wp::Payload::ComponentImportSection(_)
                 | wp::Payload::ComponentExportSection(_) => {
-                    return Err(JsError::new("unsupported section encountered in wasm"));
+                    return Err(Error::new(
+                        PrepareError::Deserialization,
+                        "unsupported section encountered in wasm",
+                    ));
                 }
             }
         }
         Ok(std::mem::replace(&mut self.output_code, Vec::new()))
     }
 
-    fn transform_import_section(
-        &mut self,
-        reader: &wp::ImportSectionReader,
-    ) -> Result<(), JsError> {
+    fn transform_import_section(&mut self, reader: &wp::ImportSectionReader) -> Result<(), Error> {
         let mut new_section = wasm_encoder::ImportSection::new();
-        for import in reader.clone() {
-            let import =
-                import.map_err(|e| JsError::new(&format!("could not parse an import: {e}")))?;
+        let mut mismatches = vec![];
+        for import in reader.clone().into_iter_with_offsets() {
+            let (offset, import) = import.map_err(invalid("could not parse an import"))?;
+            let unsupported = |error, what: &str| {
+                let message = format!("{what} cannot be imported (`{}`)", import.name);
+                Err(Error::new(error, message).at(offset))
+            };
             if import.module != "env" {
-                return Err(JsError::new("an import specifies module other than `env`"));
+                return unsupported(
+                    PrepareError::Instantiate,
+                    &format!("module other than `env` ({})", import.module),
+                );
             }
             let new_type = match import.ty {
                 wp::TypeRef::Func(id) => {
-                    // TODO: validate imported function types here.
-                    self.function_limit = self
-                        .function_limit
-                        .checked_sub(1)
-                        .ok_or_else(|| JsError::new("too many functions in the module"))?;
+                    if let Some(message) = self.check_host_function(import.name, id) {
+                        mismatches.push(ImportError {
+                            name: import.name.to_string(),
+                            message,
+                            offset,
+                        });
+                    }
+                    self.counts.import(&import.ty);
+                    if self.counts.functions > self.function_limit {
+                        let message = "the contract imports too many functions";
+                        return Err(Error::new(PrepareError::TooManyFunctions, message).at(offset));
+                    }
+                    self.function_index += 1;
                     wasm_encoder::EntityType::Function(id)
                 }
-                wp::TypeRef::Table(_) => return Err(JsError::new("tables cannot be imported")),
-                wp::TypeRef::Global(_) => return Err(JsError::new("globals cannot be imported")),
-                wp::TypeRef::Memory(_) => return Err(JsError::new("memories cannot be imported")),
-                wp::TypeRef::Tag(_) => return Err(JsError::new("tags cannot be imported")),
+                wp::TypeRef::Table(_) => return unsupported(PrepareError::Instantiate, "tables"),
+                wp::TypeRef::Global(_) => return unsupported(PrepareError::Instantiate, "globals"),
+                wp::TypeRef::Memory(_) => return unsupported(PrepareError::Memory, "memories"),
+                wp::TypeRef::Tag(_) => return unsupported(PrepareError::Deserialization, "tags"),
             };
             new_section.import(import.module, import.name, new_type);
         }
+        if !mismatches.is_empty() {
+            return Err(Error::link(mismatches));
+        }
         new_section.import("env", "memory", self.memory_import());
         // wasm_encoder a section with all imports and the imported standardized memory.
         new_section.append_to(&mut self.output_code);
         Ok(())
     }
 
+    /// Check the imported function `name` of the type `type_index` against the host functions,
+    /// the same way the runtime would when linking the contract. Returns the problem, if any.
+    fn check_host_function(&self, name: &str, type_index: u32) -> Option<String> {
+        let Some(expected) = host_function_type(self.config, name) else {
+            return Some(format!(
+                "`{name}` is not a host function in this protocol version"
+            ));
+        };
+        let Some(wp::Type::Func(actual)) = self.types.get(type_index as usize) else {
+            return Some(format!("`{name}` has an unknown type {type_index}"));
+        };
+        if actual.params() == expected.params && actual.results() == expected.results {
+            return None;
+        }
+        let actual = HostFunctionType {
+            params: actual.params(),
+            results: actual.results(),
+        };
+        Some(format!(
+            "`{name}` is imported as `{actual}` but the host function is `{expected}`"
+        ))
+    }
+
     fn ensure_import_section(&mut self) {
         if self.before_import_section {
             self.before_import_section = false;
@@ -288,31 +478,30 @@ impl<'a> PrepareContext<'a> {
         })
     }
 
-    fn copy_section(
-        &mut self,
-        id: SectionId,
-        range: std::ops::Range<usize>,
-    ) -> Result<(), JsError> {
+    fn copy_section(&mut self, id: SectionId, range: std::ops::Range<usize>) -> Result<(), Error> {
         id.encode(&mut self.output_code);
         range.len().encode(&mut self.output_code);
         self.copy(range)
     }
 
     /// Copy over the payload to the output binary without significant processing.
-    fn copy(&mut self, range: std::ops::Range<usize>) -> Result<(), JsError> {
+    fn copy(&mut self, range: std::ops::Range<usize>) -> Result<(), Error> {
+        let start = range.start;
         Ok(self.output_code.extend(self.code.get(range).ok_or_else(|| {
-            JsError::new("could not copy data from input wasm module to the output was module")
+            let message = "could not copy data from input wasm module to the output was module";
+            Error::new(PrepareError::Deserialization, message).at(start)
         })?))
     }
 }
 
-pub(crate) fn prepare_contract(original_code: &[u8]) -> Result<Vec<u8>, JsError> {
+/// Prepare the contract for execution with the limits and features specified by the `config`.
+pub(crate) fn prepare_contract(original_code: &[u8], config: &Config) -> Result<Vec<u8>, Error> {
     let features = wp::WasmFeatures {
         floats: true,
         mutable_global: true,
         sign_extension: true,
 
-        saturating_float_to_int: false,
+        saturating_float_to_int: config.saturating_float_to_int,
         reference_types: false,
         multi_value: false,
         bulk_memory: true,
@@ -329,18 +518,7 @@ pub(crate) fn prepare_contract(original_code: &[u8]) -> Result<Vec<u8>, JsError>
         memory_control: false,
         gc: false,
     };
-    let config = Config {
-        limit_config: LimitConfig {
-            max_functions_number_per_contract: Some(10_000),
-            max_locals_per_contract: Some(1_000_000),
-            initial_memory_pages: 1_024,
-            max_memory_pages: 2_048,
-        },
-        discard_custom_sections: false,
-        regular_op_cost: 3_856_371,
-    };
-
-    let lightly_steamed = PrepareContext::new(original_code, features, &config).run()?;
+    let lightly_steamed = PrepareContext::new(original_code, features, config).run()?;
     let res = finite_wasm::Analysis::new()
         .with_stack(Box::new(SimpleMaxStackCfg))
         .with_gas(Box::new(SimpleGasCostCfg(u64::from(
@@ -348,16 +526,14 @@ pub(crate) fn prepare_contract(original_code: &[u8]) -> Result<Vec<u8>, JsError>
         ))))
         .analyze(&lightly_steamed)
         .map_err(|err| {
-            JsError::new(&format!(
-                "could not finite-wasm analyze the contract: {err}"
-            ))
+            let message = format!("could not finite-wasm analyze the contract: {err}");
+            Error::new(PrepareError::Deserialization, message)
         })?
         // Make sure contracts can’t call the instrumentation functions via `env`.
         .instrument("internal", &lightly_steamed)
         .map_err(|err| {
-            JsError::new(&format!(
-                "could not finite-wasm instrument the contract: {err}"
-            ))
+            let message = format!("could not finite-wasm instrument the contract: {err}");
+            Error::new(PrepareError::Serialization, message)
         })?;
     Ok(res)
 }
@@ -426,3 +602,299 @@ impl<'a> wp::VisitOperator<'a> for SimpleGasCostCfg {
     type Output = u64;
     wp::for_each_operator!(gas_cost);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::near_vm_runner::RuntimeConfig;
+    use near_primitives_core::version::PROTOCOL_VERSION;
+
+    fn config() -> Config {
+        Config::clone(RuntimeConfig::new(PROTOCOL_VERSION).wasm_config())
+    }
+
+    /// The offsets of the imports of the `code`.
+    fn import_offsets(code: &[u8]) -> Vec<usize> {
+        for payload in wp::Parser::new(0).parse_all(code) {
+            if let wp::Payload::ImportSection(reader) = payload.unwrap() {
+                let imports = reader.into_iter_with_offsets();
+                return imports.map(|import| import.unwrap().0).collect();
+            }
+        }
+        Vec::new()
+    }
+
+    /// The offset of the code section and those of the function bodies in it.
+    fn code_offsets(code: &[u8]) -> (usize, Vec<usize>) {
+        let mut section = 0;
+        let mut bodies = Vec::new();
+        for payload in wp::Parser::new(0).parse_all(code) {
+            match payload.unwrap() {
+                wp::Payload::CodeSectionStart { range, .. } => section = range.start,
+                wp::Payload::CodeSectionEntry(func) => bodies.push(func.range().start),
+                _ => {}
+            }
+        }
+        (section, bodies)
+    }
+
+    fn assert_prepare_error(error: &Error, expected: PrepareError) {
+        assert_eq!(error.function_call_error, expected.into(), "{error}");
+    }
+
+    #[test]
+    fn test_host_function_type() {
+        let mut config = config();
+        let read_register = host_function_type(&config, "read_register").unwrap();
+        assert!(read_register.params == [wp::ValType::I64, wp::ValType::I64]);
+        assert!(read_register.results.is_empty());
+        assert_eq!(read_register.to_string(), "(i64, i64) -> ()");
+        let register_len = host_function_type(&config, "register_len").unwrap();
+        assert_eq!(register_len.to_string(), "(i64) -> (i64)");
+        assert!(host_function_type(&config, "not_a_host_function").is_none());
+        // The host functions gated behind a config flag are only there when it is set.
+        config.ed25519_verify = true;
+        assert!(host_function_type(&config, "ed25519_verify").is_some());
+        config.ed25519_verify = false;
+        assert!(host_function_type(&config, "ed25519_verify").is_none());
+    }
+
+    #[test]
+    fn test_correct_imports_pass() {
+        let code = wat::parse_str(
+            r#"(module
+                (import "env" "read_register" (func (param i64 i64)))
+                (import "env" "register_len" (func (param i64) (result i64)))
+                (func (export "main")))"#,
+        )
+        .unwrap();
+        prepare_contract(&code, &config()).unwrap();
+    }
+
+    #[test]
+    fn test_mistyped_import_is_link_error() {
+        let code = wat::parse_str(
+            r#"(module
+                (import "env" "read_register" (func (param i64 i64)))
+                (import "env" "register_len" (func (param i64) (result i32))))"#,
+        )
+        .unwrap();
+        let error = prepare_contract(&code, &config()).unwrap_err();
+        let offsets = import_offsets(&code);
+        assert_eq!(error.imports.len(), 1);
+        assert_eq!(error.imports[0].name, "register_len");
+        assert_eq!(
+            error.imports[0].message,
+            "`register_len` is imported as `(i64) -> (i32)` but the host function is \
+             `(i64) -> (i64)`"
+        );
+        assert_eq!(error.imports[0].offset, offsets[1]);
+        assert_eq!(error.offset, Some(offsets[1]));
+        assert_eq!(
+            error.function_call_error,
+            FunctionCallError::LinkError {
+                msg: error.to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn test_unknown_import_is_link_error() {
+        let code = wat::parse_str(
+            r#"(module
+                (import "env" "not_a_host_function" (func))
+                (import "env" "read_register" (func (param i64 i64)))
+                (import "env" "register_len" (func (param i32) (result i64))))"#,
+        )
+        .unwrap();
+        let error = prepare_contract(&code, &config()).unwrap_err();
+        let offsets = import_offsets(&code);
+        let imports = error
+            .imports
+            .iter()
+            .map(|import| (import.name.as_str(), import.offset))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            imports,
+            [
+                ("not_a_host_function", offsets[0]),
+                ("register_len", offsets[2])
+            ]
+        );
+        assert_eq!(
+            error.imports[0].message,
+            "`not_a_host_function` is not a host function in this protocol version"
+        );
+        assert_eq!(error.offset, Some(offsets[0]));
+        assert!(matches!(
+            error.function_call_error,
+            FunctionCallError::LinkError { .. }
+        ));
+    }
+
+    #[test]
+    fn test_too_many_imported_functions() {
+        let mut config = config();
+        config.limit_config.max_functions_number_per_contract = Some(2);
+        let code = wat::parse_str(
+            r#"(module
+                (import "env" "read_register" (func (param i64 i64)))
+                (import "env" "register_len" (func (param i64) (result i64)))
+                (import "env" "block_index" (func (result i64))))"#,
+        )
+        .unwrap();
+        let error = prepare_contract(&code, &config).unwrap_err();
+        assert_prepare_error(&error, PrepareError::TooManyFunctions);
+        assert_eq!(error.offset, Some(import_offsets(&code)[2]));
+        assert_eq!(error.function_index, None);
+    }
+
+    #[test]
+    fn test_too_many_defined_functions() {
+        let code = wat::parse_str(
+            r#"(module
+                (import "env" "block_index" (func (result i64)))
+                (func)
+                (func))"#,
+        )
+        .unwrap();
+        let mut config = config();
+        config.limit_config.max_functions_number_per_contract = Some(3);
+        prepare_contract(&code, &config).unwrap();
+        config.limit_config.max_functions_number_per_contract = Some(2);
+        let error = prepare_contract(&code, &config).unwrap_err();
+        assert_prepare_error(&error, PrepareError::TooManyFunctions);
+        assert_eq!(error.offset, Some(code_offsets(&code).0));
+    }
+
+    #[test]
+    fn test_too_many_locals() {
+        let code = wat::parse_str(
+            r#"(module
+                (import "env" "block_index" (func (result i64)))
+                (func (param i64) (local i32 i32))
+                (func (local i64 f32)))"#,
+        )
+        .unwrap();
+        let mut config = config();
+        config.limit_config.max_locals_per_contract = Some(4);
+        prepare_contract(&code, &config).unwrap();
+        // The parameters are not counted.
+        config.limit_config.max_locals_per_contract = Some(3);
+        let error = prepare_contract(&code, &config).unwrap_err();
+        assert_prepare_error(&error, PrepareError::TooManyLocals);
+        assert_eq!(
+            error.message,
+            "the contract declares too many locals (4 in total)"
+        );
+        // The imported function comes first.
+        assert_eq!(error.function_index, Some(2));
+        assert_eq!(error.offset, Some(code_offsets(&code).1[1]));
+    }
+
+    #[test]
+    fn test_imported_memory() {
+        let code = wat::parse_str(r#"(module (import "env" "memory" (memory 1)))"#).unwrap();
+        let error = prepare_contract(&code, &config()).unwrap_err();
+        assert_prepare_error(&error, PrepareError::Memory);
+        assert_eq!(error.offset, Some(import_offsets(&code)[0]));
+    }
+
+    #[test]
+    fn test_declared_memory_is_normalized() {
+        let config = config();
+        let code = wat::parse_str(r#"(module (memory 1 2) (func (export "main")))"#).unwrap();
+        let output = prepare_contract(&code, &config).unwrap();
+        let mut memories = Vec::new();
+        for payload in wp::Parser::new(0).parse_all(&output) {
+            match payload.unwrap() {
+                wp::Payload::ImportSection(reader) => {
+                    for import in reader {
+                        let import = import.unwrap();
+                        if let wp::TypeRef::Memory(memory) = import.ty {
+                            memories.push((
+                                import.module,
+                                import.name,
+                                memory.initial,
+                                memory.maximum,
+                            ));
+                        }
+                    }
+                }
+                wp::Payload::MemorySection(_) => panic!("the memory is still declared"),
+                _ => {}
+            }
+        }
+        let limits = &config.limit_config;
+        assert_eq!(
+            memories,
+            [(
+                "env",
+                "memory",
+                u64::from(limits.initial_memory_pages),
+                Some(u64::from(limits.max_memory_pages))
+            )]
+        );
+    }
+
+    #[test]
+    fn test_tables() {
+        let config = config();
+        let code = wat::parse_str(r#"(module (table 1 funcref))"#).unwrap();
+        prepare_contract(&code, &config).unwrap();
+        let code = wat::parse_str(r#"(module (import "env" "table" (table 1 funcref)))"#).unwrap();
+        let error = prepare_contract(&code, &config).unwrap_err();
+        assert_prepare_error(&error, PrepareError::Instantiate);
+        assert_eq!(error.offset, Some(import_offsets(&code)[0]));
+        // Without the reference types there can only be one table.
+        let code = wat::parse_str(r#"(module (table 1 funcref) (table 1 funcref))"#).unwrap();
+        let error = prepare_contract(&code, &config).unwrap_err();
+        assert_prepare_error(&error, PrepareError::Deserialization);
+        assert!(error.offset.is_some());
+    }
+
+    #[test]
+    fn test_import_from_other_module() {
+        let code = wat::parse_str(r#"(module (import "other" "block_index" (func (result i64))))"#)
+            .unwrap();
+        let error = prepare_contract(&code, &config()).unwrap_err();
+        assert_prepare_error(&error, PrepareError::Instantiate);
+        assert_eq!(error.offset, Some(import_offsets(&code)[0]));
+    }
+
+    #[test]
+    fn test_invalid_function() {
+        let code = wat::parse_str(
+            r#"(module
+                (func)
+                (func (result i32) (i64.const 0)))"#,
+        )
+        .unwrap();
+        let error = prepare_contract(&code, &config()).unwrap_err();
+        assert_prepare_error(&error, PrepareError::Deserialization);
+        assert_eq!(error.function_index, Some(1));
+        let (_, bodies) = code_offsets(&code);
+        assert!(error.offset.is_some_and(|offset| offset > bodies[1]));
+    }
+
+    #[test]
+    fn test_count_functions_and_locals() {
+        let code = wat::parse_str(
+            r#"(module
+                (import "env" "read_register" (func (param i64 i64)))
+                (import "env" "memory" (memory 1))
+                (import "env" "block_index" (func (result i64)))
+                (func (param i64) (local i32 i32))
+                (func (local i64)))"#,
+        )
+        .unwrap();
+        let counts = count_functions_and_locals(&code).unwrap();
+        // The memory is not a function and the parameters are not locals.
+        assert_eq!(counts.functions, 4);
+        assert_eq!(counts.locals, 3);
+        let counts = count_functions_and_locals(&wat::parse_str("(module)").unwrap()).unwrap();
+        assert_eq!((counts.functions, counts.locals), (0, 0));
+        let error = count_functions_and_locals(b"\0asm\x01\0\0\0\x0a").unwrap_err();
+        assert_prepare_error(&error, PrepareError::Deserialization);
+    }
+}